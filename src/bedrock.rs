@@ -0,0 +1,197 @@
+use std::env;
+use std::fmt;
+
+use aws_config::BehaviorVersion;
+use aws_sdk_bedrockruntime::primitives::Blob;
+use aws_sdk_bedrockruntime::Client;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info};
+
+const MODEL_ID: &str = "amazon.titan-image-generator-v1";
+// Titan image generation isn't available in every region
+const DEFAULT_REGION: &str = "us-east-1";
+
+// Titan only accepts these sizes (width, height)
+const ALLOWED_SIZES: &[(u32, u32)] = &[
+    (1024, 1024),
+    (768, 768),
+    (512, 512),
+    (768, 1152),
+    (384, 576),
+    (1152, 768),
+    (576, 384),
+    (768, 1280),
+    (384, 640),
+    (1280, 768),
+    (640, 384),
+    (896, 1152),
+    (448, 576),
+    (1152, 896),
+    (576, 448),
+    (768, 1408),
+    (384, 704),
+    (1408, 768),
+    (704, 384),
+    (640, 1408),
+    (320, 704),
+    (1408, 640),
+    (704, 320),
+    (1152, 640),
+    (1173, 640),
+];
+pub const MAX_IMAGES: u32 = 5;
+
+#[derive(strum::Display, Clone, Copy)]
+pub enum Quality {
+    #[strum(to_string = "standard")]
+    Standard,
+    #[strum(to_string = "premium")]
+    Premium,
+}
+
+pub struct ImageOptions {
+    pub width: u32,
+    pub height: u32,
+    pub quality: Quality,
+    pub count: u32,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        Self {
+            width: 512,
+            height: 512,
+            quality: Quality::Standard,
+            count: 1,
+        }
+    }
+}
+
+impl ImageOptions {
+    fn validate(&self) -> Result<(), BedrockError> {
+        if !ALLOWED_SIZES.contains(&(self.width, self.height)) {
+            return Err(BedrockError::UnsupportedSize(self.width, self.height));
+        }
+        if !(1..=MAX_IMAGES).contains(&self.count) {
+            return Err(BedrockError::InvalidCount);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum BedrockError {
+    UnsupportedSize(u32, u32),
+    InvalidCount,
+    RequestError(String),
+    Rejected,
+    ParseError(String),
+}
+
+impl fmt::Display for BedrockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BedrockError::UnsupportedSize(width, height) => write!(
+                f,
+                "{width}x{height} is not a supported size. Try 512x512, 768x768, 1024x1024, 1152x768 or 768x1152."
+            ),
+            BedrockError::InvalidCount => write!(
+                f,
+                "Between 1 and {MAX_IMAGES} images can be generated at once."
+            ),
+            BedrockError::RequestError(e) => write!(f, "Failed to generate the image: {e}"),
+            BedrockError::Rejected => write!(
+                f,
+                "The image couldn't be generated, try rephrasing the prompt."
+            ),
+            BedrockError::ParseError(e) => write!(f, "Failed to parse Bedrock response: {e}"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TitanResponse {
+    images: Vec<String>, // base64 encoded PNGs
+}
+
+/// `IMAGE_GENERATION=true` enables /imagine, the Lambda role needs bedrock:InvokeModel for it
+pub fn enabled() -> bool {
+    env::var("IMAGE_GENERATION").is_ok_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+// Only created when an image is requested, most invocations never need it
+async fn client() -> Client {
+    let region = env::var("BEDROCK_REGION").unwrap_or_else(|_| DEFAULT_REGION.to_string());
+    let config = aws_config::defaults(BehaviorVersion::latest())
+        .region(aws_config::Region::new(region))
+        .load()
+        .await;
+    Client::new(&config)
+}
+
+/// Generates PNGs with Titan, one per requested image
+pub async fn generate_images(
+    prompt: &str,
+    options: &ImageOptions,
+) -> Result<Vec<Vec<u8>>, BedrockError> {
+    // Checked here so users get a clear message instead of Bedrock's validation error
+    options.validate()?;
+
+    let body = json!({
+        "taskType": "TEXT_IMAGE",
+        "textToImageParams": { "text": prompt },
+        "imageGenerationConfig": {
+            "numberOfImages": options.count,
+            "width": options.width,
+            "height": options.height,
+            "quality": options.quality.to_string(),
+            "cfgScale": 8.0,
+        },
+    });
+
+    info!("Generating {} image(s) with {}", options.count, MODEL_ID);
+    let res = client()
+        .await
+        .invoke_model()
+        .model_id(MODEL_ID)
+        .content_type("application/json")
+        .accept("application/json")
+        .body(Blob::new(body.to_string()))
+        .send()
+        .await;
+
+    let res = match res {
+        Ok(res) => res,
+        // Prompts blocked by the content filter come back as validation errors
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_validation_exception()) =>
+        {
+            info!("Bedrock rejected the prompt: {:?}", e);
+            return Err(BedrockError::Rejected);
+        }
+        Err(e) => {
+            error!("Failed to invoke Bedrock: {:?}", e);
+            return Err(BedrockError::RequestError(e.to_string()));
+        }
+    };
+
+    let res: TitanResponse = serde_json::from_slice(res.body.as_ref())
+        .map_err(|e| BedrockError::ParseError(e.to_string()))?;
+    if res.images.is_empty() {
+        return Err(BedrockError::ParseError(
+            "no image in the response".to_string(),
+        ));
+    }
+
+    res.images
+        .iter()
+        .map(|image| {
+            base64::engine::general_purpose::STANDARD
+                .decode(image)
+                .map_err(|e| BedrockError::ParseError(e.to_string()))
+        })
+        .collect()
+}
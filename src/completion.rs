@@ -0,0 +1,152 @@
+use std::env;
+use std::fmt;
+
+use serde_json::json;
+use tracing::{error, warn};
+
+use crate::transcribe::groq_base_url;
+use crate::transcribe::TaskType;
+
+const CHAT_MODEL: &str = "llama-3.3-70b-versatile";
+
+pub const CLEAN_PROMPT: &str = "You clean up voice message transcripts. Remove filler words (um, uh, like, you know), false starts and accidental repetitions, and fix obvious transcription slips. Keep the meaning, the wording and the language of the original, don't summarize, translate or add anything. Reply with the cleaned transcript only.";
+
+pub const TODO_PROMPT: &str = "You extract action items from voice message transcripts. List only concrete tasks someone has to do, one per line, each starting with \"☐ \" and including who and when if the transcript says so. Don't add anything else, no introduction, headings or notes. Write them in the language of the transcript. If there are no action items, reply with exactly: No action items.";
+
+const SUMMARY_PROMPT: &str = "You summarize voice message transcripts. Write a short summary of a few sentences covering the main points, decisions and anything that needs a reply. Write it in the language of the transcript. Reply with the summary only, no introduction or headings.";
+
+const STRUCTURED_SUMMARY_PROMPT: &str = "You summarize conversations from voice message transcripts. The transcript is split into speaker turns, each starting with \"—\". The speakers are unknown, so call them Speaker 1, Speaker 2 and so on, and reuse a name when a turn clearly continues an earlier speaker. Go through the conversation turn by turn, one line per turn in the form \"Speaker N: what they said, in a sentence\", merging consecutive turns that say the same thing. End with a line listing the decisions and open questions, if there are any. Write it in the language of the transcript. Reply with the summary only.";
+
+/// How /summary condenses a transcript
+#[derive(strum::EnumString, Clone, Copy, Default)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum SummarizeMethod {
+    #[default]
+    #[strum(serialize = "")]
+    Plain,
+    // Who said what, from the speaker turns of the diarized transcript
+    Structured,
+}
+
+impl SummarizeMethod {
+    pub fn prompt(&self) -> &'static str {
+        match self {
+            SummarizeMethod::Plain => SUMMARY_PROMPT,
+            SummarizeMethod::Structured => STRUCTURED_SUMMARY_PROMPT,
+        }
+    }
+
+    /// The transcript the prompt expects, turns are only marked in the diarized one
+    pub fn task_type(&self) -> TaskType {
+        match self {
+            SummarizeMethod::Plain => TaskType::Transcribe,
+            SummarizeMethod::Structured => TaskType::Diarize,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CompletionError {
+    NotConfigured,
+    RequestError(String),
+    RateLimitReached,
+    ApiError(String),
+    ParseError(String),
+}
+
+impl fmt::Display for CompletionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompletionError::NotConfigured => write!(f, "Chat completion is not available."),
+            CompletionError::RequestError(e) => write!(f, "Failed to send request: {e}"),
+            CompletionError::RateLimitReached => write!(f, "Rate limit reached."),
+            CompletionError::ApiError(code) => write!(f, "Groq returned an error: {code}"),
+            CompletionError::ParseError(e) => write!(f, "Failed to parse Groq response: {e}"),
+        }
+    }
+}
+
+fn api_key() -> Option<String> {
+    env::var("GROQ_API_KEY")
+        .ok()
+        .filter(|key| !key.trim().is_empty())
+}
+
+/// The chat models run on Groq, so /clean, /summary and /todo need `GROQ_API_KEY`
+/// even when transcriptions go to OpenAI
+pub fn enabled() -> bool {
+    api_key().is_some()
+}
+
+/// Runs the text through a Groq chat model with the given system prompt
+pub async fn complete(system_prompt: &str, text: &str) -> Result<String, CompletionError> {
+    let Some(api_key) = api_key() else {
+        return Err(CompletionError::NotConfigured);
+    };
+
+    let res = reqwest::Client::new()
+        .post(format!("{}/chat/completions", groq_base_url()))
+        .bearer_auth(api_key.trim())
+        .json(&json!({
+            "model": CHAT_MODEL,
+            "temperature": 0.2,
+            "messages": [
+                { "role": "system", "content": system_prompt },
+                { "role": "user", "content": text },
+            ],
+        }))
+        .send()
+        .await
+        .map_err(|e| {
+            error!("Failed to send request to Groq: {}", e);
+            CompletionError::RequestError(e.to_string())
+        })?;
+
+    let status = res.status();
+    let json = res
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|e| CompletionError::ParseError(e.to_string()))?;
+
+    if !status.is_success() {
+        if json["error"]["code"] == "rate_limit_exceeded" {
+            warn!("Rate limit reached. Here is the response: {:?}", json);
+            return Err(CompletionError::RateLimitReached);
+        }
+        error!("Groq returned an error: {:?}", json);
+        return Err(CompletionError::ApiError(json["error"]["code"].to_string()));
+    }
+
+    let choice = &json["choices"][0];
+    let content = choice["message"]["content"]
+        .as_str()
+        .map(|content| content.trim().to_string())
+        .ok_or_else(|| CompletionError::ParseError("no message in the response".to_string()))?;
+
+    // The model ran out of output tokens, make it obvious instead of ending mid-sentence
+    if choice["finish_reason"] == "length" {
+        warn!("Completion was cut off at the token limit");
+        return Ok(format!("{content}…\n\n(truncated)"));
+    }
+
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarize_method_from_arg() {
+        assert!(matches!("".parse(), Ok(SummarizeMethod::Plain)));
+        assert!(matches!(
+            "Structured".parse(),
+            Ok(SummarizeMethod::Structured)
+        ));
+        assert!(matches!(
+            SummarizeMethod::Structured.task_type(),
+            TaskType::Diarize
+        ));
+        assert!("turns".parse::<SummarizeMethod>().is_err());
+    }
+}
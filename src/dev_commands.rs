@@ -0,0 +1,359 @@
+use std::env;
+use std::time::Duration;
+use std::time::Instant;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use reqwest::StatusCode;
+use strum::IntoEnumIterator;
+use teloxide::prelude::*;
+use teloxide::types::AllowedUpdate;
+use teloxide::types::Message;
+use teloxide::types::ReplyParameters;
+use teloxide::utils::command::BotCommands;
+use tracing::{error, info, warn};
+
+use crate::dynamodb;
+use crate::transcribe::groq_base_url;
+use crate::transcribe::rate_limits;
+use crate::transcribe::TaskType;
+use crate::utils::env_id_list;
+use crate::utils::get_unique_file_id;
+use crate::utils::mask_key;
+use crate::OPENAI_BASE_URL;
+
+// These commands are not registered with set_my_commands, so they don't show up in the menu
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase")]
+pub enum DevCommand {
+    Inspect,
+    Ping,
+    Count,
+    Keys,
+    Reset(String),
+    Usage,
+}
+
+// Scans are billed per item read, so /count only looks at one capped page
+const COUNT_SCAN_LIMIT: i32 = 1000;
+
+/// Checks if the sender is listed in `DEVELOPER_IDS` (comma-separated Telegram user ids)
+pub fn is_authorized_developer(message: &Message) -> bool {
+    let Some(user) = message.from.as_ref() else {
+        return false;
+    };
+
+    env_id_list("DEVELOPER_IDS").is_some_and(|ids| ids.contains(&(user.id.0 as i64)))
+}
+
+pub async fn handle_dev_command(
+    bot: Bot,
+    message: &Message,
+    command: DevCommand,
+    dynamodb: &aws_sdk_dynamodb::Client,
+) -> Result<lambda_http::Response<String>, lambda_http::Error> {
+    // Stay quiet for everyone else, we don't want to advertise these commands
+    if !is_authorized_developer(message) {
+        warn!("Unauthorized user tried to use a developer command");
+        return Ok(lambda_http::Response::builder()
+            .status(200)
+            .body(String::new())
+            .unwrap());
+    }
+
+    let text = match command {
+        DevCommand::Inspect => handle_inspect(message, dynamodb).await,
+        DevCommand::Ping => handle_ping().await,
+        DevCommand::Count => handle_count(dynamodb).await,
+        DevCommand::Keys => handle_keys().await,
+        DevCommand::Reset(arg) => handle_reset(&bot, &arg).await,
+        DevCommand::Usage => handle_usage(),
+    };
+
+    bot.send_message(message.chat.id, text)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .disable_notification(true)
+        .await
+        .unwrap();
+
+    Ok(lambda_http::Response::builder()
+        .status(200)
+        .body(String::new())
+        .unwrap())
+}
+
+async fn handle_inspect(message: &Message, dynamodb: &aws_sdk_dynamodb::Client) -> String {
+    let Some(unique_file_id) = message.reply_to_message().and_then(get_unique_file_id) else {
+        return "Reply to a voice message, video note or video to inspect its cache entry".into();
+    };
+
+    info!("Inspecting cache for unique_file_id: {}", unique_file_id);
+
+    let item = match dynamodb::get_raw_item(dynamodb, unique_file_id).await {
+        Ok(Some(item)) => item,
+        Ok(None) => return format!("No cache entry for {unique_file_id}"),
+        Err(e) => {
+            error!("Failed to get item from DynamoDB: {:?}", e);
+            return format!("Failed to query DynamoDB: {e}");
+        }
+    };
+
+    let mut lines = vec![format!("Cache entry for {unique_file_id}")];
+    for task_type in TaskType::iter() {
+        let line = match item.get(&task_type.to_string()).and_then(|v| v.as_s().ok()) {
+            Some(text) => format!("{task_type}: cached ({} chars)", text.chars().count()),
+            None => format!("{task_type}: not cached"),
+        };
+        lines.push(line);
+    }
+
+    let expires_at = match item.get("expires_at") {
+        Some(AttributeValue::N(timestamp)) => timestamp.clone(),
+        _ => "not set".to_string(),
+    };
+    lines.push(format!("expires_at: {expires_at}"));
+
+    lines.join("\n")
+}
+
+// Everything the bot handles. Set ALLOWED_UPDATES (comma-separated, e.g. "message,callback_query")
+// to receive less, `message` is always included.
+const DEFAULT_ALLOWED_UPDATES: [AllowedUpdate; 4] = [
+    AllowedUpdate::Message,
+    AllowedUpdate::EditedMessage,
+    AllowedUpdate::CallbackQuery,
+    AllowedUpdate::ChannelPost,
+];
+
+fn allowed_updates() -> Vec<AllowedUpdate> {
+    let Ok(names) = env::var("ALLOWED_UPDATES") else {
+        return DEFAULT_ALLOWED_UPDATES.to_vec();
+    };
+
+    let mut updates = vec![AllowedUpdate::Message];
+    for name in names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        match serde_json::from_value::<AllowedUpdate>(serde_json::Value::from(name)) {
+            Ok(update) if !updates.contains(&update) => updates.push(update),
+            Ok(_) => {}
+            Err(_) => warn!("Ignoring unknown update type in ALLOWED_UPDATES: {}", name),
+        }
+    }
+    updates
+}
+
+fn update_names(updates: &[AllowedUpdate]) -> String {
+    updates
+        .iter()
+        .filter_map(|update| serde_json::to_value(update).ok())
+        .filter_map(|name| name.as_str().map(str::to_string))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Only a dry run unless confirmed, dropped updates can't be recovered
+async fn handle_reset(bot: &Bot, arg: &str) -> String {
+    let info = match bot.get_webhook_info().await {
+        Ok(info) => info,
+        Err(e) => {
+            error!("Failed to get webhook info: {:?}", e);
+            return format!("Failed to get webhook info: {e}");
+        }
+    };
+    let pending = info.pending_update_count;
+    let allowed_updates = allowed_updates();
+    // Telegram leaves this out when the webhook gets every update type except a few opt-in ones
+    let current_updates = info
+        .allowed_updates
+        .as_deref()
+        .map_or("default".to_string(), update_names);
+
+    if arg.trim() != "confirm" {
+        return format!(
+            "{pending} pending update(s) would be dropped.\nAllowed updates: {current_updates} -> {}\nNothing was changed, use /reset confirm to drop them.",
+            update_names(&allowed_updates)
+        );
+    }
+
+    let Some(url) = info.url else {
+        return "No webhook is set, nothing to reset".into();
+    };
+
+    // setWebhook replaces the allowed updates too, so this is a good moment to sync them
+    let request = bot
+        .set_webhook(url)
+        .drop_pending_updates(true)
+        .allowed_updates(allowed_updates.clone());
+
+    match request.await {
+        Ok(_) => {
+            warn!("Dropped {} pending updates", pending);
+            format!(
+                "Dropped {pending} pending update(s)\nAllowed updates: {}",
+                update_names(&allowed_updates)
+            )
+        }
+        Err(e) => {
+            error!("Failed to reset the webhook: {:?}", e);
+            format!("Failed to reset the webhook: {e}")
+        }
+    }
+}
+
+// A health check has to answer even when the API hangs
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn health_check_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(HEALTH_CHECK_TIMEOUT)
+        .build()
+        .unwrap()
+}
+
+async fn handle_ping() -> String {
+    let Ok(api_key) = env::var("GROQ_API_KEY") else {
+        return "GROQ_API_KEY is not set!".into();
+    };
+
+    let now = Instant::now();
+    let res = health_check_client()
+        .get(format!("{}/models", groq_base_url()))
+        .bearer_auth(api_key.trim())
+        .send()
+        .await;
+    let elapsed = now.elapsed().as_millis();
+
+    match res {
+        Ok(res) => format!(
+            "Groq key loaded\nGET /models: {} ({elapsed}ms)",
+            res.status()
+        ),
+        Err(e) if e.is_timeout() => {
+            warn!("Groq didn't answer within {:?}", HEALTH_CHECK_TIMEOUT);
+            format!(
+                "Groq key loaded\nGET /models timed out after {}s",
+                HEALTH_CHECK_TIMEOUT.as_secs()
+            )
+        }
+        Err(e) => {
+            error!("Failed to reach Groq: {:?}", e);
+            format!("Groq key loaded\nGET /models failed: {e}")
+        }
+    }
+}
+
+async fn handle_count(dynamodb: &aws_sdk_dynamodb::Client) -> String {
+    let (items, truncated) = match dynamodb::scan_items(dynamodb, COUNT_SCAN_LIMIT).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!("Failed to scan DynamoDB: {:?}", e);
+            return format!("Failed to scan DynamoDB: {e}");
+        }
+    };
+
+    // Settings, processing locks, leaderboards, feedback and last transcripts share the table, only count cached clips
+    let clips: Vec<_> = items
+        .iter()
+        .filter(|item| {
+            item.get("id")
+                .and_then(|id| id.as_s().ok())
+                .is_some_and(|id| {
+                    !["settings_", "lock_", "stats_", "feedback_", "last_"]
+                        .iter()
+                        .any(|prefix| id.starts_with(prefix))
+                })
+        })
+        .collect();
+
+    let mut lines = vec![format!("Cached clips: {}", clips.len())];
+    for task_type in TaskType::iter() {
+        let count = clips
+            .iter()
+            .filter(|item| item.contains_key(&task_type.to_string()))
+            .count();
+        lines.push(format!("{task_type}: {count}"));
+    }
+    if truncated {
+        lines.push(format!(
+            "(estimate, only the first {COUNT_SCAN_LIMIT} items were scanned)"
+        ));
+    }
+
+    lines.join("\n")
+}
+
+fn handle_usage() -> String {
+    let rate_limits = rate_limits();
+    if rate_limits.is_empty() {
+        return "No rate limits seen yet. They are read from the Whisper responses, so transcribe something first.".into();
+    }
+
+    let mut lines = vec!["Rate limits from the last response (this container only)".to_string()];
+    for (key, limits) in rate_limits {
+        lines.push(String::new());
+        lines.push(format!(
+            "{key}, {}s ago",
+            limits.updated.elapsed().as_secs()
+        ));
+        for (name, value) in limits.headers {
+            lines.push(format!("{name}: {value}"));
+        }
+    }
+
+    lines.join("\n")
+}
+
+async fn handle_keys() -> String {
+    let keys = [
+        ("Groq", groq_base_url(), env::var("GROQ_API_KEY")),
+        (
+            "OpenAI",
+            OPENAI_BASE_URL.to_string(),
+            env::var("OPENAI_API_KEY"),
+        ),
+    ];
+
+    let client = health_check_client();
+    let mut lines = Vec::new();
+    for (provider, base_url, api_key) in keys {
+        let Some(api_key) = api_key.ok().filter(|key| !key.trim().is_empty()) else {
+            lines.push(format!("{provider}: not set"));
+            continue;
+        };
+        let api_key = api_key.trim();
+
+        // Listing models is free, but still checks the key and its rate limit
+        let res = client
+            .get(format!("{base_url}/models"))
+            .bearer_auth(api_key)
+            .send()
+            .await;
+        let status = match res {
+            Ok(res) if res.status().is_success() => "OK".to_string(),
+            Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => "rate limited".to_string(),
+            Ok(res)
+                if res.status() == StatusCode::UNAUTHORIZED
+                    || res.status() == StatusCode::FORBIDDEN =>
+            {
+                "invalid auth".to_string()
+            }
+            Ok(res) => format!("unexpected status {}", res.status()),
+            Err(e) if e.is_timeout() => {
+                warn!(
+                    "{} didn't answer within {:?}",
+                    provider, HEALTH_CHECK_TIMEOUT
+                );
+                format!("timed out after {}s", HEALTH_CHECK_TIMEOUT.as_secs())
+            }
+            Err(e) => {
+                error!("Failed to reach {}: {:?}", provider, e);
+                format!("request failed: {e}")
+            }
+        };
+        lines.push(format!("{provider} ({}): {status}", mask_key(api_key)));
+    }
+
+    lines.join("\n")
+}
@@ -1,20 +1,414 @@
+use std::collections::HashMap;
 use std::env;
+use std::sync::Once;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use aws_sdk_dynamodb::{types::AttributeValue, Client, Error};
-use tracing::info;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use aws_sdk_dynamodb::{Client, Error};
+use teloxide::types::{ChatId, MessageId, User};
+use tracing::{debug, error, info};
 
 use crate::transcribe::TaskType;
 
 pub struct DBItem {
     pub text: String,
     pub unique_file_id: String,
-    pub task_type: String,
+    pub task_type: TaskType,
+    pub duration: u32, // in seconds
 }
 
 pub enum ItemReturnInfo {
-    Text(String),
-    Exists, // Item already exists, but for other task type.
+    Text(String, Option<u32>), // Text and audio duration (older items don't have it)
+    Exists,                    // Item already exists, but for other task type.
     None,
+    CacheDisabled, // DYNAMODB_TABLE is not set, don't read or write anything.
+}
+
+// Items are removed by the DynamoDB TTL on the expires_at attribute
+const DEFAULT_CACHE_TTL_DAYS: u64 = 7;
+// Only a transcript this recent counts as a repeat (see is_repeated_transcript)
+const DUPLICATE_WINDOW_SECONDS: u64 = 10 * 60;
+// A processing lock older than this is considered abandoned (longer than the Lambda timeout)
+const LOCK_SECONDS: u64 = 90;
+
+static MISSING_TABLE: Once = Once::new();
+
+// Returns None when DYNAMODB_TABLE is not set, in which case caching is skipped entirely
+fn get_table_name() -> Option<String> {
+    match env::var("DYNAMODB_TABLE") {
+        Ok(table) if !table.trim().is_empty() => Some(table),
+        _ => {
+            // Only log once per container, not on every request
+            MISSING_TABLE.call_once(|| error!("DYNAMODB_TABLE is not set, caching is disabled!"));
+            None
+        }
+    }
+}
+
+pub fn caching_enabled() -> bool {
+    get_table_name().is_some()
+}
+
+#[derive(Debug)]
+pub enum SettingError {
+    CacheDisabled, // DYNAMODB_TABLE is not set, so there is nowhere to save it
+    DynamoDb(Error),
+}
+
+impl From<Error> for SettingError {
+    fn from(err: Error) -> Self {
+        SettingError::DynamoDb(err)
+    }
+}
+
+#[derive(strum::Display, strum::EnumIter, Clone, Copy)]
+pub enum ChatSetting {
+    #[strum(to_string = "auto_transcribe")]
+    AutoTranscribe,
+    #[strum(to_string = "duration_footer")]
+    DurationFooter,
+    #[strum(to_string = "reply_quoting")]
+    ReplyQuoting,
+    #[strum(to_string = "silent_replies")]
+    SilentReplies,
+    #[strum(to_string = "leaderboard")]
+    Leaderboard,
+    #[strum(to_string = "private_mode")]
+    PrivateMode,
+    #[strum(to_string = "dedupe_replies")]
+    DedupeReplies,
+}
+
+impl ChatSetting {
+    // Used when the chat never changed the setting
+    pub fn default_value(&self) -> bool {
+        match self {
+            ChatSetting::AutoTranscribe => true,
+            ChatSetting::DurationFooter => true,
+            ChatSetting::ReplyQuoting => true,
+            ChatSetting::SilentReplies => true,
+            ChatSetting::Leaderboard => false, // opt-in, it stores who sent what
+            ChatSetting::PrivateMode => false,
+            ChatSetting::DedupeReplies => false,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ChatSetting::AutoTranscribe => "Automatic transcription",
+            ChatSetting::DurationFooter => "Duration footer",
+            ChatSetting::ReplyQuoting => "Replying to the audio",
+            ChatSetting::SilentReplies => "Silent replies",
+            ChatSetting::Leaderboard => "Leaderboard",
+            ChatSetting::PrivateMode => "Private mode",
+            ChatSetting::DedupeReplies => "Skipping repeated transcriptions",
+        }
+    }
+
+    pub fn command(&self) -> &'static str {
+        match self {
+            ChatSetting::AutoTranscribe => "auto",
+            ChatSetting::DurationFooter => "duration",
+            ChatSetting::ReplyQuoting => "quote",
+            ChatSetting::SilentReplies => "silent",
+            ChatSetting::Leaderboard => "leaderboard",
+            ChatSetting::PrivateMode => "private",
+            ChatSetting::DedupeReplies => "dedupe",
+        }
+    }
+}
+
+// Settings that hold a name instead of on/off
+#[derive(strum::Display, Clone, Copy)]
+pub enum TextSetting {
+    #[strum(to_string = "tts_voice")]
+    TtsVoice,
+    #[strum(to_string = "language")]
+    Language,
+    #[strum(to_string = "whisper_model")]
+    WhisperModel,
+}
+
+/// Every setting of a chat, read with one request. Anything the chat never changed is the default.
+#[derive(Default)]
+pub struct ChatSettings {
+    item: HashMap<String, AttributeValue>,
+}
+
+impl ChatSettings {
+    pub fn enabled(&self, setting: ChatSetting) -> bool {
+        self.item
+            .get(&setting.to_string())
+            .and_then(|value| value.as_bool().ok().copied())
+            .unwrap_or(setting.default_value())
+    }
+
+    // Stored as the name, so values that are no longer valid just fall back to the default
+    pub fn text(&self, setting: TextSetting) -> Option<&str> {
+        self.item
+            .get(&setting.to_string())
+            .and_then(|value| value.as_s().ok())
+            .map(String::as_str)
+    }
+}
+
+// Chat settings live in the same table as the transcriptions, under their own key prefix
+fn settings_key(chat_id: ChatId) -> AttributeValue {
+    AttributeValue::S(format!("settings_{}", chat_id))
+}
+
+// Per-user totals for /leaderboard, these don't expire
+fn stats_key(chat_id: ChatId) -> AttributeValue {
+    AttributeValue::S(format!("stats_{}", chat_id))
+}
+
+pub enum LockStatus {
+    Acquired,
+    Locked, // Someone else is already processing this clip
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// `CACHE_TTL_DAYS` sets how long cached transcriptions and feedback are kept (default 7)
+pub fn cache_ttl_days() -> u64 {
+    env::var("CACHE_TTL_DAYS")
+        .ok()
+        .and_then(|days| days.trim().parse().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(DEFAULT_CACHE_TTL_DAYS)
+}
+
+fn expires_at() -> AttributeValue {
+    AttributeValue::N((now() + cache_ttl_days() * 24 * 60 * 60).to_string())
+}
+
+fn lock_key(chat_id: ChatId, unique_file_id: &str, task_type: &TaskType) -> AttributeValue {
+    AttributeValue::S(format!("lock_{}_{}_{}", chat_id, unique_file_id, task_type))
+}
+
+pub async fn get_settings(client: &Client, chat_id: ChatId) -> Result<ChatSettings, Error> {
+    let Some(table) = get_table_name() else {
+        return Ok(ChatSettings::default());
+    };
+
+    let result = client
+        .get_item()
+        .table_name(table)
+        .key("id", settings_key(chat_id))
+        .send()
+        .await?;
+
+    Ok(ChatSettings {
+        item: result.item.unwrap_or_default(),
+    })
+}
+
+pub async fn set_setting(
+    client: &Client,
+    chat_id: ChatId,
+    setting: ChatSetting,
+    value: bool,
+) -> Result<(), SettingError> {
+    let Some(table) = get_table_name() else {
+        debug!("Caching is disabled, not saving setting");
+        return Err(SettingError::CacheDisabled);
+    };
+    let setting = setting.to_string();
+
+    info!("Setting '{}' to {} for chat {}", setting, value, chat_id);
+
+    client
+        .update_item()
+        .table_name(table)
+        .key("id", settings_key(chat_id))
+        .update_expression(format!("SET #{} = :value", setting))
+        .expression_attribute_names(format!("#{}", setting), setting)
+        .expression_attribute_values(":value", AttributeValue::Bool(value))
+        .send()
+        .await
+        .map_err(Error::from)?;
+
+    Ok(())
+}
+
+/// `None` removes the setting, going back to the default
+pub async fn set_text_setting(
+    client: &Client,
+    chat_id: ChatId,
+    setting: TextSetting,
+    value: Option<String>,
+) -> Result<(), SettingError> {
+    let Some(table) = get_table_name() else {
+        debug!("Caching is disabled, not saving setting");
+        return Err(SettingError::CacheDisabled);
+    };
+    let setting = setting.to_string();
+
+    info!("Setting '{}' to {:?} for chat {}", setting, value, chat_id);
+
+    let request = client
+        .update_item()
+        .table_name(table)
+        .key("id", settings_key(chat_id))
+        .expression_attribute_names(format!("#{}", setting), &setting);
+    let request = match value {
+        Some(value) => request
+            .update_expression(format!("SET #{} = :value", setting))
+            .expression_attribute_values(":value", AttributeValue::S(value)),
+        None => request.update_expression(format!("REMOVE #{}", setting)),
+    };
+    request.send().await.map_err(Error::from)?;
+
+    Ok(())
+}
+
+/// Remembers the chat's latest transcript and returns whether the previous one,
+/// sent within DUPLICATE_WINDOW_SECONDS, had exactly the same text
+pub async fn is_repeated_transcript(
+    client: &Client,
+    chat_id: ChatId,
+    text: &str,
+) -> Result<bool, Error> {
+    let Some(table) = get_table_name() else {
+        return Ok(false);
+    };
+    let now = now();
+
+    // One request for both, the old values come back from the update
+    let result = client
+        .update_item()
+        .table_name(table)
+        .key("id", AttributeValue::S(format!("last_{}", chat_id)))
+        .update_expression("SET #text = :text, sent_at = :now, expires_at = :expires_at")
+        .expression_attribute_names("#text", "text")
+        .expression_attribute_values(":text", AttributeValue::S(text.to_string()))
+        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+        .expression_attribute_values(
+            ":expires_at",
+            AttributeValue::N((now + DUPLICATE_WINDOW_SECONDS).to_string()),
+        )
+        .return_values(ReturnValue::UpdatedOld)
+        .send()
+        .await?;
+
+    let previous = result.attributes.unwrap_or_default();
+    let same_text = previous
+        .get("text")
+        .and_then(|value| value.as_s().ok())
+        .is_some_and(|previous| previous == text);
+    let recent = previous
+        .get("sent_at")
+        .and_then(|value| value.as_n().ok())
+        .and_then(|sent_at| sent_at.parse::<u64>().ok())
+        .is_some_and(|sent_at| now.saturating_sub(sent_at) < DUPLICATE_WINDOW_SECONDS);
+
+    Ok(same_text && recent)
+}
+
+/// Stores a user's correction of a transcription, expiring like the cached transcriptions
+pub async fn add_feedback(
+    client: &Client,
+    chat_id: ChatId,
+    message_id: MessageId,
+    unique_file_id: Option<&str>,
+    original: &str,
+    correction: &str,
+) -> Result<(), Error> {
+    let Some(table) = get_table_name() else {
+        debug!("Caching is disabled, not saving feedback");
+        return Ok(());
+    };
+
+    let mut request = client
+        .put_item()
+        .table_name(table)
+        .item(
+            "id",
+            AttributeValue::S(format!("feedback_{}_{}", chat_id, message_id)),
+        )
+        .item("original", AttributeValue::S(original.to_string()))
+        .item("correction", AttributeValue::S(correction.to_string()))
+        .item("expires_at", expires_at());
+    if let Some(unique_file_id) = unique_file_id {
+        request = request.item(
+            "unique_file_id",
+            AttributeValue::S(unique_file_id.to_string()),
+        );
+    }
+    request.send().await?;
+
+    Ok(())
+}
+
+/// Adds the audio duration to the user's total in the chat and remembers their current name
+pub async fn add_user_seconds(
+    client: &Client,
+    chat_id: ChatId,
+    user: &User,
+    seconds: u32,
+) -> Result<(), Error> {
+    let Some(table) = get_table_name() else {
+        return Ok(());
+    };
+    let name = match &user.username {
+        Some(username) => format!("@{username}"),
+        None => user.full_name(),
+    };
+
+    client
+        .update_item()
+        .table_name(table)
+        .key("id", stats_key(chat_id))
+        .update_expression("ADD #seconds :seconds SET #name = :name")
+        .expression_attribute_names("#seconds", format!("seconds_{}", user.id))
+        .expression_attribute_names("#name", format!("name_{}", user.id))
+        .expression_attribute_values(":seconds", AttributeValue::N(seconds.to_string()))
+        .expression_attribute_values(":name", AttributeValue::S(name))
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+/// Returns the name and total seconds of every user in the chat, most seconds first
+pub async fn get_leaderboard(
+    client: &Client,
+    chat_id: ChatId,
+) -> Result<Vec<(String, u64)>, Error> {
+    let Some(table) = get_table_name() else {
+        return Ok(Vec::new());
+    };
+
+    let result = client
+        .get_item()
+        .table_name(table)
+        .key("id", stats_key(chat_id))
+        .send()
+        .await?;
+    let Some(item) = result.item else {
+        return Ok(Vec::new());
+    };
+
+    let mut leaderboard: Vec<(String, u64)> = item
+        .iter()
+        .filter_map(|(key, value)| {
+            let user_id = key.strip_prefix("seconds_")?;
+            let seconds = value.as_n().ok()?.parse().ok()?;
+            let name = item
+                .get(&format!("name_{user_id}"))
+                .and_then(|name| name.as_s().ok().cloned())
+                .unwrap_or_else(|| user_id.to_string());
+            Some((name, seconds))
+        })
+        .collect();
+    leaderboard.sort_by_key(|(_, seconds)| std::cmp::Reverse(*seconds));
+
+    Ok(leaderboard)
 }
 
 pub async fn get_item(
@@ -22,7 +416,9 @@ pub async fn get_item(
     unique_file_id: &String,
     task_type: &TaskType,
 ) -> Result<ItemReturnInfo, Error> {
-    let table = env::var("DYNAMODB_TABLE").unwrap();
+    let Some(table) = get_table_name() else {
+        return Ok(ItemReturnInfo::CacheDisabled);
+    };
     let key = AttributeValue::S(unique_file_id.to_string());
     let task_type = task_type.to_string();
 
@@ -47,7 +443,12 @@ pub async fn get_item(
             return Ok(ItemReturnInfo::None);
         }
 
-        let transcription = item.first().unwrap().get(&task_type);
+        let item = item.first().unwrap();
+        let transcription = item.get(&task_type);
+        let duration = item
+            .get("duration")
+            .and_then(|duration| duration.as_n().ok())
+            .and_then(|duration| duration.parse().ok());
 
         match transcription {
             Some(transcription) => {
@@ -56,7 +457,7 @@ pub async fn get_item(
                     task_type, unique_file_id
                 );
                 let transcription = transcription.as_s().unwrap().to_string();
-                Ok(ItemReturnInfo::Text(transcription))
+                Ok(ItemReturnInfo::Text(transcription, duration))
             }
             None => {
                 info!(
@@ -72,13 +473,51 @@ pub async fn get_item(
     }
 }
 
+pub async fn get_raw_item(
+    client: &Client,
+    unique_file_id: &String,
+) -> Result<Option<HashMap<String, AttributeValue>>, Error> {
+    let Some(table) = get_table_name() else {
+        return Ok(None);
+    };
+    let key = AttributeValue::S(unique_file_id.to_string());
+
+    let result = client
+        .get_item()
+        .table_name(table)
+        .key("id", key)
+        .send()
+        .await?;
+
+    Ok(result.item)
+}
+
+/// Returns up to `limit` items from one scan page, and whether the table has more
+pub async fn scan_items(
+    client: &Client,
+    limit: i32,
+) -> Result<(Vec<HashMap<String, AttributeValue>>, bool), Error> {
+    let Some(table) = get_table_name() else {
+        return Ok((Vec::new(), false));
+    };
+
+    let result = client.scan().table_name(table).limit(limit).send().await?;
+    let truncated = result.last_evaluated_key.is_some();
+
+    Ok((result.items.unwrap_or_default(), truncated))
+}
+
 pub async fn append_attribute(
     client: &Client,
     unique_file_id: &String,
     task_type: &TaskType,
     text: &String,
+    duration: u32,
 ) -> Result<(), Error> {
-    let table = env::var("DYNAMODB_TABLE").unwrap();
+    let Some(table) = get_table_name() else {
+        debug!("Caching is disabled, not updating item");
+        return Ok(());
+    };
     let key = AttributeValue::S(unique_file_id.to_string());
     let task_type = task_type.to_string();
     let text = AttributeValue::S(text.to_string());
@@ -92,27 +531,142 @@ pub async fn append_attribute(
         .update_item()
         .table_name(table)
         .key("id", key)
-        .update_expression(format!("SET #{} = :text", task_type))
+        .update_expression(format!(
+            "SET #{} = :text, expires_at = :expires_at, #duration = if_not_exists(#duration, :duration)",
+            task_type
+        ))
         .expression_attribute_names(format!("#{}", task_type), task_type)
+        .expression_attribute_names("#duration", "duration")
         .expression_attribute_values(":text", text)
+        .expression_attribute_values(":duration", AttributeValue::N(duration.to_string()))
+        .expression_attribute_values(":expires_at", expires_at())
         .send()
         .await?;
 
     Ok(())
 }
 
-pub async fn add_item(client: &Client, item: DBItem) -> Result<(), Error> {
-    let table = env::var("DYNAMODB_TABLE").unwrap();
-    let text = AttributeValue::S(item.text);
-    let file_id = AttributeValue::S(item.unique_file_id);
+/// Takes a short-lived lock so a retried webhook doesn't transcribe and reply to the same clip twice
+pub async fn acquire_lock(
+    client: &Client,
+    chat_id: ChatId,
+    unique_file_id: &str,
+    task_type: &TaskType,
+) -> Result<LockStatus, Error> {
+    let Some(table) = get_table_name() else {
+        return Ok(LockStatus::Acquired);
+    };
+    let now = now();
+    let locked_until = AttributeValue::N((now + LOCK_SECONDS).to_string());
 
-    client
+    // The TTL cleanup can take days, so stale locks are overwritten based on locked_until
+    let res = client
         .put_item()
         .table_name(table)
-        .item(item.task_type, text)
-        .item("id", file_id)
+        .item("id", lock_key(chat_id, unique_file_id, task_type))
+        .item("locked_until", locked_until.clone())
+        .item("expires_at", locked_until)
+        .condition_expression("attribute_not_exists(id) OR locked_until < :now")
+        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+        .send()
+        .await;
+
+    match res {
+        Ok(_) => Ok(LockStatus::Acquired),
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            Ok(LockStatus::Locked)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn release_lock(
+    client: &Client,
+    chat_id: ChatId,
+    unique_file_id: &str,
+    task_type: &TaskType,
+) -> Result<(), Error> {
+    let Some(table) = get_table_name() else {
+        return Ok(());
+    };
+
+    client
+        .delete_item()
+        .table_name(table)
+        .key("id", lock_key(chat_id, unique_file_id, task_type))
         .send()
         .await?;
 
     Ok(())
 }
+
+/// Pushes `expires_at` back on a cache hit, so clips that keep getting requested stay cached
+pub async fn touch_item(client: &Client, unique_file_id: &String) -> Result<(), Error> {
+    let Some(table) = get_table_name() else {
+        return Ok(());
+    };
+    let key = AttributeValue::S(unique_file_id.to_string());
+
+    info!(
+        "Extending expiration for unique_file_id '{}'",
+        unique_file_id
+    );
+
+    client
+        .update_item()
+        .table_name(table)
+        .key("id", key)
+        .update_expression("SET expires_at = :expires_at")
+        .expression_attribute_values(":expires_at", expires_at())
+        .send()
+        .await?;
+
+    Ok(())
+}
+
+pub async fn add_item(client: &Client, item: DBItem) -> Result<(), Error> {
+    let Some(table) = get_table_name() else {
+        debug!("Caching is disabled, not saving item");
+        return Ok(());
+    };
+
+    // A put replaces the whole item, which would drop anything another request saved in the meantime
+    let res = client
+        .put_item()
+        .table_name(table)
+        .item(
+            item.task_type.to_string(),
+            AttributeValue::S(item.text.clone()),
+        )
+        .item("id", AttributeValue::S(item.unique_file_id.clone()))
+        .item("duration", AttributeValue::N(item.duration.to_string()))
+        .item("expires_at", expires_at())
+        .condition_expression("attribute_not_exists(id)")
+        .send()
+        .await;
+
+    match res {
+        Ok(_) => Ok(()),
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            info!(
+                "Item for unique_file_id '{}' was created concurrently, updating it instead",
+                item.unique_file_id
+            );
+            append_attribute(
+                client,
+                &item.unique_file_id,
+                &item.task_type,
+                &item.text,
+                item.duration,
+            )
+            .await
+        }
+        Err(e) => Err(e.into()),
+    }
+}
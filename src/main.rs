@@ -1,33 +1,96 @@
 use aws_config::meta::region::RegionProviderChain;
 use aws_config::BehaviorVersion;
+use bytes::Bytes;
+use completion::SummarizeMethod;
 use core::str;
+use dev_commands::DevCommand;
+use dynamodb::ChatSetting;
+use dynamodb::ChatSettings;
 use dynamodb::ItemReturnInfo;
-use lambda_http::{run, service_fn, Body, Error, Request};
+use dynamodb::LockStatus;
+use dynamodb::TextSetting;
+use lambda_http::http::header::{HeaderValue, CONTENT_TYPE};
+use lambda_http::http::Method;
+use lambda_http::{run, service_fn, Body, Error, Request, RequestExt};
+use messages::{Lang, Msg};
 use mime::Mime;
 use std::env;
 use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use strum::IntoEnumIterator;
 use teloxide::types::ChatAction;
+use teloxide::types::InlineKeyboardButton;
+use teloxide::types::InlineKeyboardMarkup;
+use teloxide::types::InputFile;
+use teloxide::types::InputMedia;
+use teloxide::types::InputMediaPhoto;
+use teloxide::types::Me;
 use teloxide::types::Message;
 use teloxide::types::MessageId;
+use teloxide::types::ParseMode;
 use teloxide::types::ReplyParameters;
+use teloxide::types::ThreadId;
 use teloxide::types::UpdateKind;
 use teloxide::utils::command::BotCommands;
+use teloxide::utils::html;
 use teloxide::{net::Download, prelude::*};
-use tracing::{debug, error, info, warn};
+use tokio::sync::OnceCell;
+use tokio::sync::Semaphore;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use tracing_subscriber::fmt;
 use transcribe::TaskType;
+use transcribe::TranscriptionError;
+use transcribe::TranscriptionProvider;
+use transcribe::WhisperModel;
+use utils::audio_heading;
+use utils::audio_mime;
+use utils::caption_prompt;
 use utils::delete_message_delay;
+use utils::env_id_list;
+use utils::format_as_markdown_parts;
+use utils::format_duration;
+use utils::get_audio_duration;
+use utils::get_unique_file_id;
+use utils::has_audio;
+use utils::is_chat_admin;
+use utils::parse_time_range;
+use utils::resolve_mime;
+use utils::source_mime;
 use utils::split_string;
+use utils::start_typing_indicator;
+use utils::zip_files;
+use utils::SUPPORTED_MIME_TYPES;
 
+mod bedrock;
+mod completion;
+mod dev_commands;
 mod dynamodb;
+mod messages;
+mod metrics;
+mod remote_audio;
+mod transcode;
 mod transcribe;
+mod tts;
 mod utils;
 
 const MAX_DURATION: u32 = 30; // in minutes
 const MAX_FILE_SIZE: u32 = 25; // in MB (groq whisper limit)
 const DEFAULT_DELAY: u64 = 5;
+const LEADERBOARD_SIZE: usize = 10; // users shown by /leaderboard
+
+// Cold start time, reported by /health
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static TRANSCRIPTION_SLOTS: OnceLock<Semaphore> = OnceLock::new();
+// The bot's own user never changes, so get_me is only called until it succeeds once
+static ME: OnceCell<Me> = OnceCell::const_new();
+const DEFAULT_CONCURRENT_TRANSCRIPTIONS: usize = 4;
+const TRANSCRIPTION_QUEUE_SECONDS: u64 = 10;
+const PROGRESS_MESSAGE_MINUTES: u32 = 5; // clips this long get a progress message
+const DEFAULT_MIN_DURATION_SECONDS: u32 = 1;
 
 pub const BASE_URL: &str = "https://api.groq.com/openai/v1";
+pub const OPENAI_BASE_URL: &str = "https://api.openai.com/v1";
 
 #[derive(BotCommands, Clone)]
 #[command(rename_rule = "lowercase")]
@@ -36,14 +99,94 @@ enum BotCommand {
     Help,
     #[command(description = "welcome message")]
     Start,
-    #[command(description = "transcribe the replied audio")]
-    Transcribe,
+    #[command(
+        description = "transcribe the replied audio, optionally only a range like 10:00-12:00"
+    )]
+    Transcribe(String),
     #[command(description = "transcribe & translate the replied audio file in English.", aliases = ["english", "en"])]
     Translate,
+    #[command(
+        description = "transcribe the replied audio and translate it to English in one reply"
+    )]
+    Both,
+    #[command(
+        description = "transcribe the replied audio, splitting it where the speaker seems to change"
+    )]
+    Diarize,
+    #[command(
+        description = "transcribe the replied audio without dropping segments that seem silent"
+    )]
+    Raw,
+    #[command(
+        description = "send the transcription of the replied message as a code block for easy copying"
+    )]
+    Code,
+    #[command(description = "remove filler words and repetitions from the transcription")]
+    Clean,
+    #[command(description = "list the action items from the replied audio or transcription")]
+    Todo,
+    #[command(
+        description = "summarize the replied audio or transcription, /summary structured goes turn by turn"
+    )]
+    Summary(String),
+    #[command(
+        description = "reply to a transcription with the corrected text to report a mistake"
+    )]
+    Feedback(String),
+    #[command(description = "get the raw Whisper response for the replied audio as a JSON file")]
+    Json,
+    #[command(
+        description = "transcribe the replied audio with [M:SS] timestamps every ~15 seconds"
+    )]
+    Stamps,
+    #[command(
+        description = "get the transcript, English translation and subtitles of the replied audio as a zip"
+    )]
+    Export,
+    #[command(
+        description = "read the text (or the replied message) out loud, optionally starting with a voice"
+    )]
+    Tts(String),
+    #[command(description = "set the default /tts voice for this chat (or random)")]
+    Voice(String),
+    #[command(description = "list the /tts voices")]
+    Voices,
+    #[command(
+        description = "pick the Whisper model for this chat (large, or distil for English only)"
+    )]
+    Model(String),
+    #[command(description = "generate an image from the text")]
+    Imagine(String),
+    #[command(description = "set the language of the bot's messages")]
+    Lang(String),
+    #[command(description = "show the bot version and transcription model")]
+    About,
+    #[command(description = "show the settings of this chat")]
+    Settings,
+    #[command(description = "toggle automatic transcription of voice messages (on/off)")]
+    Auto(String),
+    #[command(description = "toggle the audio duration shown under transcriptions (on/off)")]
+    Duration(String),
+    #[command(description = "toggle sending transcriptions as a reply to the audio (on/off)")]
+    Quote(String),
+    #[command(description = "toggle sending transcriptions without a notification (on/off)")]
+    Silent(String),
+    #[command(description = "show who sent the most transcribed audio (on/off to toggle)")]
+    Leaderboard(String),
+    #[command(
+        description = "toggle private mode, transcriptions of this chat are never cached (on/off)"
+    )]
+    Private(String),
+    #[command(
+        description = "toggle replying with a short note when a transcription repeats the previous one (on/off)"
+    )]
+    Dedupe(String),
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
+    STARTED.get_or_init(Instant::now);
+
     // Initialize tracing for logging
     fmt()
         .with_max_level(tracing::Level::INFO)
@@ -55,15 +198,33 @@ async fn main() -> Result<(), Error> {
     let bot = Bot::new(env::var("TELEGRAM_BOT_TOKEN").expect("TELEGRAM_BOT_TOKEN not set!"));
 
     // Setup AWS DynamoDB conn
+    // AWS_REGION (set by Lambda) wins, eu-central-1 is only the fallback for local runs
     let region_provider = RegionProviderChain::default_provider().or_else("eu-central-1");
     let config = aws_config::defaults(BehaviorVersion::latest())
         .region(region_provider)
         .load()
         .await;
-    let dynamodb = aws_sdk_dynamodb::Client::new(&config);
+    // DYNAMODB_ENDPOINT_URL points the cache at DynamoDB Local, e.g. http://localhost:8000
+    let mut dynamodb_config = aws_sdk_dynamodb::config::Builder::from(&config);
+    if let Ok(endpoint) = env::var("DYNAMODB_ENDPOINT_URL") {
+        if !endpoint.trim().is_empty() {
+            info!("Using DynamoDB endpoint {}", endpoint.trim());
+            dynamodb_config = dynamodb_config.endpoint_url(endpoint.trim());
+        }
+    }
+    let dynamodb = aws_sdk_dynamodb::Client::from_conf(dynamodb_config.build());
+
+    // Fetched once per cold start instead of on the first command, see bot_me
+    bot_me(&bot).await;
+
+    // Reads the provider config on the cold start, so a missing key shows up in the logs right away
+    info!(
+        "Transcribing with {}",
+        transcribe::Provider::from_env().description()
+    );
 
     // Set commands
-    let res = bot.set_my_commands(BotCommand::bot_commands()).await;
+    let res = bot.set_my_commands(available_commands()).await;
 
     if let Err(e) = res {
         warn!("Failed to set commands: {:?}", e);
@@ -78,6 +239,63 @@ async fn handler(
     bot: &Bot,
     dynamodb: &aws_sdk_dynamodb::Client,
 ) -> Result<lambda_http::Response<String>, lambda_http::Error> {
+    // Every log line of the invocation carries the id, so a failed response can be matched to its logs
+    let request_id = request_id(&req);
+    let res = handle_request(req, bot, dynamodb)
+        .instrument(info_span!("request", id = %request_id))
+        .await?;
+    if res.status().is_success() {
+        return Ok(res);
+    }
+
+    warn!(
+        "Responding with {} ({}) to request {}",
+        res.status(),
+        res.body(),
+        request_id
+    );
+    let (mut parts, body) = res.into_parts();
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let body = serde_json::json!({ "error": body, "request_id": request_id });
+    Ok(lambda_http::Response::from_parts(parts, body.to_string()))
+}
+
+// The Lambda request id, so it matches the REPORT lines in CloudWatch too
+fn request_id(req: &lambda_http::Request) -> String {
+    match req.lambda_context_ref() {
+        Some(context) if !context.request_id.is_empty() => context.request_id.clone(),
+        _ => {
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or_default();
+            format!("local-{nanos:x}")
+        }
+    }
+}
+
+async fn handle_request(
+    req: lambda_http::Request,
+    bot: &Bot,
+    dynamodb: &aws_sdk_dynamodb::Client,
+) -> Result<lambda_http::Response<String>, lambda_http::Error> {
+    // Uptime monitors can ping this without sending a fake update
+    if req.method() == Method::GET && req.uri().path() == "/health" {
+        let uptime = STARTED.get().map(Instant::elapsed).unwrap_or_default();
+        let body = serde_json::json!({
+            "status": "ok",
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime_seconds": uptime.as_secs(),
+        });
+        return Ok(lambda_http::Response::builder()
+            .status(200)
+            .header("content-type", "application/json")
+            .body(body.to_string())
+            .unwrap());
+    }
+
     // Parse JSON webhook
     let bot = bot.clone();
 
@@ -92,20 +310,82 @@ async fn handler(
         }
     };
 
+    // ALLOWED_CHAT_IDS keeps private deployments (and their API keys) to the listed chats
+    if let (Some(allowed), Some(chat)) = (env_id_list("ALLOWED_CHAT_IDS"), update.chat()) {
+        if !allowed.contains(&chat.id.0) {
+            debug!("Ignoring update from chat {}, it's not allowed", chat.id);
+            return Ok(lambda_http::Response::builder()
+                .status(200)
+                .body(String::new())
+                .unwrap());
+        }
+    }
+
+    if let (Some(blocked), Some(user)) = (env_id_list("BLOCKED_USER_IDS"), update.from()) {
+        if blocked.contains(&(user.id.0 as i64)) {
+            info!("Ignoring update from blocked user {}", user.id);
+            return Ok(lambda_http::Response::builder()
+                .status(200)
+                .body(String::new())
+                .unwrap());
+        }
+    }
+
+    let settings = &load_settings(dynamodb, update.chat().map(|chat| chat.id)).await;
+
     match update.kind {
-        UpdateKind::Message(message) => {
+        // Channel posts have no `from`, anything that needs the sender has to handle that
+        UpdateKind::Message(message) | UpdateKind::ChannelPost(message) => {
+            // A channel's posts are copied into its discussion group as automatic forwards.
+            // The post itself arrives as a channel_post, so don't transcribe it a second time there.
+            let auto_transcribe =
+                settings.enabled(ChatSetting::AutoTranscribe) && !message.is_automatic_forward();
+
             // Handle commands
             if let Some(text) = &message.text() {
-                if let Ok(command) = BotCommand::parse(text, bot.get_me().await.unwrap().username())
-                {
-                    return handle_command(bot.clone(), &message, command, dynamodb).await;
+                let username = bot_username(&bot).await;
+                if let Ok(command) = BotCommand::parse(text, username) {
+                    return handle_command(bot.clone(), &message, command, dynamodb, settings)
+                        .await;
+                }
+                if let Ok(command) = DevCommand::parse(text, username) {
+                    return dev_commands::handle_dev_command(
+                        bot.clone(),
+                        &message,
+                        command,
+                        dynamodb,
+                    )
+                    .await;
+                }
+
+                // Handle links to audio files
+                if let Some((url, mime)) = remote_audio::find_audio_url(text) {
+                    if auto_transcribe {
+                        return handle_url_message(&bot, &message, url, mime, settings).await;
+                    }
                 }
             }
 
             // Handle audio messages and video notes
-            if message.voice().is_some() || message.video_note().is_some() {
-                return handle_audio_message(message, bot.clone(), dynamodb, TaskType::Transcribe)
-                    .await;
+            if (message.voice().is_some() || message.video_note().is_some()) && auto_transcribe {
+                if below_min_duration(&message) {
+                    info!(
+                        "Skipping {:?}, it's below the minimum duration",
+                        get_unique_file_id(&message)
+                    );
+                    return Ok(lambda_http::Response::builder()
+                        .status(200)
+                        .body(String::new())
+                        .unwrap());
+                }
+                return handle_audio_message(
+                    message,
+                    bot.clone(),
+                    dynamodb,
+                    settings,
+                    TaskType::Transcribe,
+                )
+                .await;
             }
 
             // Return 200 OK for non-audio messages & non-commands
@@ -114,6 +394,12 @@ async fn handler(
                 .body(String::new())
                 .unwrap())
         }
+        UpdateKind::CallbackQuery(query) => {
+            handle_callback_query(bot, query, dynamodb, settings).await
+        }
+        UpdateKind::EditedMessage(message) => {
+            handle_edited_message(bot, message, dynamodb, settings).await
+        }
         _ => {
             debug!("Received non-message update");
             Ok(lambda_http::Response::builder()
@@ -129,46 +415,248 @@ async fn handle_command(
     message: &Message,
     command: BotCommand,
     dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
 ) -> Result<lambda_http::Response<String>, lambda_http::Error> {
     match command {
         BotCommand::Help => {
-            bot.send_message(message.chat.id, BotCommand::descriptions().to_string())
+            bot.send_message(message.chat.id, help_text(message))
                 .await
                 .unwrap();
         }
         BotCommand::Start => {
-            bot.send_message(message.chat.id, "Welcome! Send a voice message or video note to transcribe it. You can also use /help to see all available commands.")
+            let lang = chat_lang(settings);
+            bot.send_message(message.chat.id, messages::text(lang, Msg::Welcome))
+                .await
+                .unwrap();
+        }
+        BotCommand::Tts(arg) => {
+            handle_tts_command(&bot, message, &arg, settings).await;
+        }
+        BotCommand::Voice(arg) => {
+            handle_voice_command(&bot, message, &arg, dynamodb, settings).await;
+        }
+        BotCommand::Model(arg) => {
+            handle_model_command(&bot, message, &arg, dynamodb, settings).await;
+        }
+        BotCommand::Voices => {
+            let voices: Vec<String> = tts::Voice::iter().map(|voice| voice.to_string()).collect();
+            bot.send_message(
+                message.chat.id,
+                format!(
+                    "Available voices: {}\nUse /voice <name> to pick one for this chat.",
+                    voices.join(", ")
+                ),
+            )
+            .reply_parameters(ReplyParameters::new(message.id))
+            .await
+            .unwrap();
+        }
+        BotCommand::Imagine(prompt) => {
+            handle_imagine_command(&bot, message, &prompt, settings).await;
+        }
+        BotCommand::Lang(arg) => {
+            handle_lang_command(&bot, message, &arg, dynamodb, settings).await;
+        }
+        BotCommand::About => {
+            let text = format!(
+                "duck_transcriber v{}\nTranscription: {}",
+                env!("CARGO_PKG_VERSION"),
+                chat_provider(settings).description()
+            );
+            bot.send_message(message.chat.id, text)
+                .reply_parameters(ReplyParameters::new(message.id))
                 .await
                 .unwrap();
         }
         BotCommand::Translate => {
             // Handle audio messages and video notes in the reply
             if let Some(reply) = message.reply_to_message() {
-                if reply.voice().is_some()
-                    || reply.video_note().is_some()
-                    || reply.video().is_some()
-                {
+                if has_audio(reply) {
                     return handle_audio_message(
                         reply.clone(),
                         bot.clone(),
                         dynamodb,
+                        settings,
                         TaskType::Translate,
                     )
                     .await;
                 }
             }
         }
-        BotCommand::Transcribe => {
+        BotCommand::Settings => {
+            handle_settings_command(&bot, message, settings).await;
+        }
+        BotCommand::Auto(arg) => {
+            handle_setting_command(
+                &bot,
+                message,
+                ChatSetting::AutoTranscribe,
+                &arg,
+                dynamodb,
+                settings,
+            )
+            .await;
+        }
+        BotCommand::Duration(arg) => {
+            handle_setting_command(
+                &bot,
+                message,
+                ChatSetting::DurationFooter,
+                &arg,
+                dynamodb,
+                settings,
+            )
+            .await;
+        }
+        BotCommand::Quote(arg) => {
+            handle_setting_command(
+                &bot,
+                message,
+                ChatSetting::ReplyQuoting,
+                &arg,
+                dynamodb,
+                settings,
+            )
+            .await;
+        }
+        BotCommand::Silent(arg) => {
+            handle_setting_command(
+                &bot,
+                message,
+                ChatSetting::SilentReplies,
+                &arg,
+                dynamodb,
+                settings,
+            )
+            .await;
+        }
+        BotCommand::Leaderboard(arg) => {
+            handle_leaderboard_command(&bot, message, &arg, dynamodb, settings).await;
+        }
+        BotCommand::Private(arg) => {
+            handle_setting_command(
+                &bot,
+                message,
+                ChatSetting::PrivateMode,
+                &arg,
+                dynamodb,
+                settings,
+            )
+            .await;
+        }
+        BotCommand::Dedupe(arg) => {
+            handle_setting_command(
+                &bot,
+                message,
+                ChatSetting::DedupeReplies,
+                &arg,
+                dynamodb,
+                settings,
+            )
+            .await;
+        }
+        BotCommand::Code => {
+            if let Some(reply) = message.reply_to_message() {
+                handle_code_command(&bot, reply, dynamodb, settings).await;
+            }
+        }
+        BotCommand::Clean => {
+            if let Some(reply) = message.reply_to_message() {
+                handle_clean_command(&bot, reply, dynamodb, settings).await;
+            }
+        }
+        BotCommand::Todo => {
+            if let Some(reply) = message.reply_to_message() {
+                handle_todo_command(&bot, reply, dynamodb, settings).await;
+            }
+        }
+        BotCommand::Summary(arg) => match arg.trim().parse::<SummarizeMethod>() {
+            Ok(method) => {
+                if let Some(reply) = message.reply_to_message() {
+                    handle_summary_command(&bot, reply, method, dynamodb, settings).await;
+                }
+            }
+            Err(_) => {
+                bot.send_message(
+                    message.chat.id,
+                    "Usage: /summary for a short summary, or /summary structured for who said what, turn by turn.",
+                )
+                .reply_parameters(ReplyParameters::new(message.id))
+                .await
+                .unwrap();
+            }
+        },
+        BotCommand::Both => {
+            if let Some(reply) = message.reply_to_message() {
+                if has_audio(reply) {
+                    handle_both_command(&bot, reply, dynamodb, settings).await;
+                }
+            }
+        }
+        BotCommand::Feedback(correction) => {
+            handle_feedback_command(&bot, message, &correction, dynamodb).await;
+        }
+        BotCommand::Json => {
+            if let Some(reply) = message.reply_to_message() {
+                if has_audio(reply) {
+                    handle_json_command(&bot, reply, settings).await;
+                }
+            }
+        }
+        BotCommand::Stamps => {
+            if let Some(reply) = message.reply_to_message() {
+                if has_audio(reply) {
+                    handle_stamps_command(&bot, reply, settings).await;
+                }
+            }
+        }
+        BotCommand::Export => {
+            if let Some(reply) = message.reply_to_message() {
+                if has_audio(reply) {
+                    handle_export_command(&bot, reply, dynamodb, settings).await;
+                }
+            }
+        }
+        BotCommand::Raw => {
+            if let Some(reply) = message.reply_to_message() {
+                if has_audio(reply) {
+                    return handle_audio_message(
+                        reply.clone(),
+                        bot.clone(),
+                        dynamodb,
+                        settings,
+                        TaskType::Raw,
+                    )
+                    .await;
+                }
+            }
+        }
+        BotCommand::Diarize => {
+            if let Some(reply) = message.reply_to_message() {
+                if has_audio(reply) {
+                    return handle_audio_message(
+                        reply.clone(),
+                        bot.clone(),
+                        dynamodb,
+                        settings,
+                        TaskType::Diarize,
+                    )
+                    .await;
+                }
+            }
+        }
+        BotCommand::Transcribe(range) => {
             // Handle audio messages and video notes in the reply
             if let Some(reply) = message.reply_to_message() {
-                if reply.voice().is_some()
-                    || reply.video_note().is_some()
-                    || reply.video().is_some()
-                {
+                if has_audio(reply) {
+                    if !range.trim().is_empty() {
+                        return handle_range_command(&bot, message, reply, &range, settings).await;
+                    }
                     return handle_audio_message(
                         reply.clone(),
                         bot.clone(),
                         dynamodb,
+                        settings,
                         TaskType::Transcribe,
                     )
                     .await;
@@ -183,141 +671,1834 @@ async fn handle_command(
         .unwrap())
 }
 
-async fn handle_audio_message(
-    message: Message,
+// Leaves out the commands that don't work with the current configuration
+fn available_commands() -> Vec<teloxide::types::BotCommand> {
+    // Settings are stored in DynamoDB, without it they can't be changed
+    let mut settings: Vec<String> = ChatSetting::iter()
+        .map(|setting| format!("/{}", setting.command()))
+        .collect();
+    settings.extend(["/voice", "/lang", "/model", "/feedback", "/settings"].map(str::to_string));
+
+    BotCommand::bot_commands()
+        .into_iter()
+        .filter(|command| dynamodb::caching_enabled() || !settings.contains(&command.command))
+        .filter(|command| bedrock::enabled() || command.command != "/imagine")
+        .filter(|command| {
+            completion::enabled()
+                || !["/clean", "/summary", "/todo"].contains(&command.command.as_str())
+        })
+        .filter(|command| {
+            tts::enabled() || !["/tts", "/voice", "/voices"].contains(&command.command.as_str())
+        })
+        .collect()
+}
+
+fn help_text(message: &Message) -> String {
+    let mut lines: Vec<String> = available_commands()
+        .into_iter()
+        .map(|command| format!("{} — {}", command.command, command.description))
+        .collect();
+
+    lines.push(String::new());
+    lines.push(
+        "Voice messages, video notes and links to audio files are transcribed automatically."
+            .to_string(),
+    );
+
+    if dev_commands::is_authorized_developer(message) {
+        let commands: Vec<String> = DevCommand::bot_commands()
+            .into_iter()
+            .map(|command| command.command)
+            .collect();
+        lines.push(String::new());
+        lines.push(format!("Developer commands: {}", commands.join(", ")));
+    }
+
+    lines.join("\n")
+}
+
+// Only a command added to the caption is handled, the audio itself was already transcribed when it was sent
+async fn handle_edited_message(
     bot: Bot,
+    message: Message,
     dynamodb: &aws_sdk_dynamodb::Client,
-    task_type: TaskType,
+    settings: &ChatSettings,
 ) -> Result<lambda_http::Response<String>, lambda_http::Error> {
-    let unique_file_id: &String;
+    let command = match message.caption() {
+        Some(caption) if has_audio(&message) => {
+            BotCommand::parse(caption, bot_username(&bot).await).ok()
+        }
+        _ => None,
+    };
 
-    // Send "typing" indicator
-    debug!("Sending typing indicator");
-    let action = bot
-        .send_chat_action(message.chat.id, ChatAction::Typing)
-        .await;
-    if let Err(e) = action {
-        warn!("Failed to send typing indicator: {:?}", e);
-    }
+    let task_type = match command {
+        // A range is only part of the clip, so it's never a repeat of the automatic transcription
+        Some(BotCommand::Transcribe(range)) if !range.trim().is_empty() => {
+            info!(
+                "Caption of an audio message was edited to /transcribe {}",
+                range.trim()
+            );
+            return handle_range_command(&bot, &message, &message, &range, settings).await;
+        }
+        Some(BotCommand::Transcribe(_)) => TaskType::Transcribe,
+        Some(BotCommand::Translate) => TaskType::Translate,
+        Some(BotCommand::Diarize) => TaskType::Diarize,
+        Some(BotCommand::Raw) => TaskType::Raw,
+        _ => {
+            debug!("Ignoring edited message");
+            return Ok(lambda_http::Response::builder()
+                .status(200)
+                .body(String::new())
+                .unwrap());
+        }
+    };
 
-    // Check if the message is a voice or video note or just a video
-    if let Some(voice) = message.voice() {
-        let filemeta = &voice.file;
-        unique_file_id = &filemeta.unique_id;
-        info!("Received voice message!");
-    } else if let Some(video_note) = message.video_note() {
-        let filemeta = &video_note.file;
-        unique_file_id = &filemeta.unique_id;
-        info!("Received video note!");
-    } else if let Some(video_file) = message.video() {
-        let filemeta = &video_file.file;
-        unique_file_id = &filemeta.unique_id;
-        info!("Received video message!");
-    } else {
-        unreachable!();
+    // The chat already got the transcription when the audio arrived, /raw only differs in the odd segment
+    if matches!(task_type, TaskType::Transcribe | TaskType::Raw)
+        && transcribed_automatically(settings, &message)
+    {
+        info!(
+            "Ignoring caption edit to /{}, the audio was already transcribed",
+            task_type
+        );
+        return Ok(lambda_http::Response::builder()
+            .status(200)
+            .body(String::new())
+            .unwrap());
     }
 
-    // Get the transcription from DynamoDB
-    let item = dynamodb::get_item(dynamodb, unique_file_id, &task_type).await;
-    let transcription_type = if let Ok(transcription) = item {
-        match transcription {
-            ItemReturnInfo::Text(transcription) => {
-                info!(
-                    "Transcription found in DynamoDB for unique_file_id: {}",
-                    unique_file_id
-                );
+    info!("Caption of an audio message was edited to /{}", task_type);
+    handle_audio_message(message, bot, dynamodb, settings, task_type).await
+}
 
-                // Send the transcription to the user
-                safe_send(&bot, message.chat.id, Some(&transcription), message.id).await;
+async fn handle_callback_query(
+    bot: Bot,
+    query: CallbackQuery,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) -> Result<lambda_http::Response<String>, lambda_http::Error> {
+    // Always answer, otherwise the button keeps spinning
+    if let Err(e) = bot.answer_callback_query(&query.id).await {
+        warn!("Failed to answer callback query: {:?}", e);
+    }
 
-                return Ok(lambda_http::Response::builder()
-                    .status(200)
-                    .body(String::new())
-                    .unwrap());
-            }
-            ItemReturnInfo::Exists => {
-                info!(
-                    "Item exists in DynamoDB for unique_file_id: {} but for other task type",
-                    unique_file_id
-                );
-                ItemReturnInfo::Exists
-            }
-            ItemReturnInfo::None => {
-                info!("No items found for unique_file_id: {}", unique_file_id);
-                ItemReturnInfo::None
-            }
+    let action = query.data.as_deref().and_then(|data| data.split_once(':'));
+    let (task_type, unique_file_id) = match action {
+        Some(("translate", unique_file_id)) => (TaskType::Translate, unique_file_id.to_string()),
+        _ => {
+            debug!("Ignoring unknown callback query data: {:?}", query.data);
+            return Ok(lambda_http::Response::builder()
+                .status(200)
+                .body(String::new())
+                .unwrap());
         }
-    } else {
-        error!(
-            "Failed to get item from DynamoDB: {:?}",
-            item.err().unwrap()
-        );
-        ItemReturnInfo::None // if something happens ignore the db
     };
 
-    // (audio_bytes, mime, duration) = download_audio(&bot, &message).await?;
-    let res = download_audio(&bot, &message).await;
-    if let Err(e) = res {
-        error!("Failed to download audio: {:?}", e);
-        let bot_msg = bot
-            .send_message(message.chat.id, format!("ERROR: {e}"))
-            .reply_parameters(ReplyParameters::new(message.id))
-            .disable_notification(true)
-            .await
-            .unwrap();
-
-        delete_message_delay(&bot, &bot_msg, DEFAULT_DELAY).await;
-
+    // The button is attached to our reply, which replies to the original audio
+    let Some(message) = query.regular_message() else {
+        debug!("Callback query message is no longer accessible");
         return Ok(lambda_http::Response::builder()
             .status(200)
             .body(String::new())
             .unwrap());
-    }
-
-    let (audio_bytes, mime, duration) = res.unwrap();
+    };
+    let audio = message.reply_to_message();
 
-    // If the duration is above MAX_DURATION
-    if duration > MAX_DURATION * 60 {
-        warn!("The audio message is above {MAX_DURATION} minutes!");
-        bot.send_message(
+    // Try the cache first, this works even if the original audio was deleted
+    if let Ok(ItemReturnInfo::Text(text, duration)) =
+        get_cached_item(dynamodb, settings, &unique_file_id, &task_type).await
+    {
+        info!(
+            "{} found in DynamoDB for callback on unique_file_id: {}",
+            task_type, unique_file_id
+        );
+        if let Err(e) = dynamodb::touch_item(dynamodb, &unique_file_id).await {
+            error!("Failed to extend expiration in DynamoDB: {:?}", e);
+        }
+        let reply_to = audio.map_or(message.id, |audio| audio.id);
+        let footer = duration_footer(settings, duration);
+        let options = send_options(settings, message, reply_to);
+        safe_send(
+            &bot,
             message.chat.id,
-            format!("Duration is above {} minutes", MAX_DURATION * 60),
+            Some(&format!("{text}{footer}")),
+            &options,
+            None,
         )
-        .reply_parameters(ReplyParameters::new(message.id))
-        .disable_notification(true)
-        .await
-        .unwrap();
-
-        // we don't want to delete the message
-        // Return early if the audio is too long
+        .await;
         return Ok(lambda_http::Response::builder()
             .status(200)
             .body(String::new())
             .unwrap());
     }
 
-    // Transcribe the message
+    match audio {
+        Some(audio) => {
+            handle_audio_message(audio.clone(), bot.clone(), dynamodb, settings, task_type).await
+        }
+        None => {
+            warn!("Original audio for callback query not found");
+            let bot_msg = bot
+                .send_message(
+                    message.chat.id,
+                    messages::text(chat_lang(settings), Msg::AudioNotFound),
+                )
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await;
+            match bot_msg {
+                Ok(bot_msg) => delete_message_delay(&bot, &bot_msg, DEFAULT_DELAY).await,
+                Err(e) => warn!("Failed to send message: {:?}", e),
+            }
+            Ok(lambda_http::Response::builder()
+                .status(200)
+                .body(String::new())
+                .unwrap())
+        }
+    }
+}
+
+async fn handle_code_command(
+    bot: &Bot,
+    message: &Message,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    // Works on our own transcription messages as well as on already transcribed audio
+    let text = if let Some(text) = message.text() {
+        Some(text.to_string())
+    } else if let Some(unique_file_id) = get_unique_file_id(message) {
+        match get_cached_item(dynamodb, settings, unique_file_id, &TaskType::Transcribe).await {
+            Ok(ItemReturnInfo::Text(text, _)) => Some(text),
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    let Some(text) = text else {
+        bot.send_message(
+            message.chat.id,
+            "Nothing to format. Transcribe the audio with /transcribe first, then reply to it with /code.",
+        )
+        .reply_parameters(ReplyParameters::new(message.id))
+        .disable_notification(true)
+        .await
+        .unwrap();
+        return;
+    };
+
+    // Each message has to be a complete code block, so long text is split before formatting
+    for part in format_as_markdown_parts(&text, 4096) {
+        bot.send_message(message.chat.id, part)
+            .parse_mode(ParseMode::MarkdownV2)
+            .reply_parameters(ReplyParameters::new(message.id))
+            .disable_notification(true)
+            .await
+            .unwrap();
+    }
+}
+
+async fn handle_tts_command(bot: &Bot, message: &Message, arg: &str, settings: &ChatSettings) {
+    // "/tts nova hello" uses that voice, otherwise the chat's default or a random one
+    let arg = arg.trim();
+    let (voice, text) = match arg
+        .split_once(char::is_whitespace)
+        .and_then(|(voice, text)| Some((voice.parse::<tts::Voice>().ok()?, text.trim())))
+    {
+        Some((voice, text)) => (voice, text),
+        None => (chat_voice(settings), arg),
+    };
+    let text = if text.is_empty() {
+        message
+            .reply_to_message()
+            .and_then(|reply| reply.text().or(reply.caption()))
+            .unwrap_or_default()
+    } else {
+        text
+    };
+
+    if text.is_empty() {
+        bot.send_message(
+            message.chat.id,
+            "Send /tts followed by some text, or reply to a message with /tts.",
+        )
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+        return;
+    }
+
+    let typing = start_typing_indicator(bot, message.chat.id);
+    let speech = tts::speak(text, voice).await;
+    drop(typing);
+
+    match speech {
+        Ok(speech) => {
+            bot.send_voice(message.chat.id, InputFile::memory(speech))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+        }
+        Err(e) => {
+            warn!("Failed to generate speech: {}", e);
+            let bot_msg = bot
+                .send_message(
+                    message.chat.id,
+                    messages::tts_error(chat_lang(settings), &e),
+                )
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+}
+
+fn chat_voice(settings: &ChatSettings) -> tts::Voice {
+    settings
+        .text(TextSetting::TtsVoice)
+        .and_then(|voice| voice.parse().ok())
+        .unwrap_or_else(tts::Voice::random)
+}
+
+fn chat_model(settings: &ChatSettings) -> WhisperModel {
+    settings
+        .text(TextSetting::WhisperModel)
+        .and_then(|model| model.parse().ok())
+        .unwrap_or_default()
+}
+
+fn chat_provider(settings: &ChatSettings) -> transcribe::Provider {
+    transcribe::Provider::from_env().with_model(chat_model(settings))
+}
+
+fn chat_lang(settings: &ChatSettings) -> Lang {
+    settings
+        .text(TextSetting::Language)
+        .and_then(|lang| lang.parse().ok())
+        .unwrap_or_default()
+}
+
+async fn handle_lang_command(
+    bot: &Bot,
+    message: &Message,
+    arg: &str,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    let current = chat_lang(settings);
+    let Ok(lang) = arg.trim().parse::<Lang>() else {
+        bot.send_message(
+            message.chat.id,
+            messages::text(current, Msg::LanguageUsage(current)),
+        )
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+        return;
+    };
+
+    if !is_chat_admin(bot, message).await {
+        bot.send_message(message.chat.id, messages::text(current, Msg::AdminOnly))
+            .reply_parameters(ReplyParameters::new(message.id))
+            .await
+            .unwrap();
+        return;
+    }
+
+    let text = match dynamodb::set_text_setting(
+        dynamodb,
+        message.chat.id,
+        TextSetting::Language,
+        Some(lang.to_string()),
+    )
+    .await
+    {
+        Ok(_) => messages::text(lang, Msg::LanguageSet),
+        Err(e) => setting_error("language", e),
+    };
+
+    bot.send_message(message.chat.id, text)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+}
+
+async fn handle_voice_command(
+    bot: &Bot,
+    message: &Message,
+    arg: &str,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    let arg = arg.trim().to_lowercase();
+    let voice = match arg.as_str() {
+        "" => {
+            let text = match settings.text(TextSetting::TtsVoice) {
+                Some(voice) => {
+                    format!("The /tts voice is {voice}. Use /voice random to change it back.")
+                }
+                None => "The /tts voice is random. Use /voice <name> to pick one, see /voices."
+                    .to_string(),
+            };
+            bot.send_message(message.chat.id, text)
+                .reply_parameters(ReplyParameters::new(message.id))
+                .await
+                .unwrap();
+            return;
+        }
+        "random" => None,
+        name => match name.parse::<tts::Voice>() {
+            Ok(voice) => Some(voice),
+            Err(_) => {
+                bot.send_message(
+                    message.chat.id,
+                    format!("Unknown voice: {name}. See /voices for the list."),
+                )
+                .reply_parameters(ReplyParameters::new(message.id))
+                .await
+                .unwrap();
+                return;
+            }
+        },
+    };
+
+    if !is_chat_admin(bot, message).await {
+        let lang = chat_lang(settings);
+        bot.send_message(message.chat.id, messages::text(lang, Msg::AdminOnly))
+            .reply_parameters(ReplyParameters::new(message.id))
+            .await
+            .unwrap();
+        return;
+    }
+
+    let text = match dynamodb::set_text_setting(
+        dynamodb,
+        message.chat.id,
+        TextSetting::TtsVoice,
+        voice.map(|voice| voice.to_string()),
+    )
+    .await
+    {
+        Ok(_) => match voice {
+            Some(voice) => format!("/tts will use the {voice} voice."),
+            None => "/tts will use a random voice.".to_string(),
+        },
+        Err(e) => setting_error("TTS voice", e),
+    };
+
+    bot.send_message(message.chat.id, text)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+}
+
+async fn handle_model_command(
+    bot: &Bot,
+    message: &Message,
+    arg: &str,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        let models: Vec<String> = WhisperModel::iter()
+            .map(|model| model.to_string())
+            .collect();
+        bot.send_message(
+            message.chat.id,
+            format!(
+                "This chat uses the {} model ({}). Use /model <name> to change it: {}.",
+                chat_model(settings),
+                chat_model(settings).id(),
+                models.join(", ")
+            ),
+        )
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+        return;
+    }
+    let Ok(model) = arg.parse::<WhisperModel>() else {
+        bot.send_message(
+            message.chat.id,
+            format!("Unknown model: {arg}. Use /model to see the list."),
+        )
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+        return;
+    };
+
+    if !is_chat_admin(bot, message).await {
+        let lang = chat_lang(settings);
+        bot.send_message(message.chat.id, messages::text(lang, Msg::AdminOnly))
+            .reply_parameters(ReplyParameters::new(message.id))
+            .await
+            .unwrap();
+        return;
+    }
+
+    // The default isn't stored, so a changed default applies to chats that never picked one
+    let value = match model {
+        WhisperModel::Large => None,
+        model => Some(model.to_string()),
+    };
+    let text = match dynamodb::set_text_setting(
+        dynamodb,
+        message.chat.id,
+        TextSetting::WhisperModel,
+        value,
+    )
+    .await
+    {
+        Ok(_) if model.english_only() => format!(
+            "Transcriptions will use {}. It's faster, but only works well for English audio and can't translate.",
+            model.id()
+        ),
+        Ok(_) => format!("Transcriptions will use {}.", model.id()),
+        Err(e) => setting_error("Whisper model", e),
+    };
+
+    bot.send_message(message.chat.id, text)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+}
+
+// Leading options: "hd" for premium quality, "<width>x<height>" and "n=<count>"
+fn parse_imagine_args(arg: &str) -> Result<(bedrock::ImageOptions, &str), String> {
+    let mut options = bedrock::ImageOptions::default();
+    let mut rest = arg.trim();
+
+    while let Some((word, remaining)) = rest.split_once(char::is_whitespace) {
+        if word.eq_ignore_ascii_case("hd") {
+            options.quality = bedrock::Quality::Premium;
+        } else if let Some(count) = word.strip_prefix("n=") {
+            options.count = count
+                .parse()
+                .map_err(|_| format!("Invalid image count: {count}"))?;
+        } else if let Some((width, height)) = word
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        {
+            options.width = width;
+            options.height = height;
+        } else {
+            break;
+        }
+        rest = remaining.trim_start();
+    }
+
+    Ok((options, rest))
+}
+
+async fn handle_imagine_command(bot: &Bot, message: &Message, arg: &str, settings: &ChatSettings) {
+    // Not registered when disabled, but it can still be typed
+    if !bedrock::enabled() {
+        return;
+    }
+    let (options, prompt) = match parse_imagine_args(arg) {
+        Ok(args) => args,
+        Err(e) => {
+            bot.send_message(message.chat.id, e)
+                .reply_parameters(ReplyParameters::new(message.id))
+                .await
+                .unwrap();
+            return;
+        }
+    };
+    if prompt.is_empty() {
+        bot.send_message(
+            message.chat.id,
+            "Send /imagine followed by a description of the image. Options go first: hd, a size like 1024x1024, n=<1-5>.",
+        )
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+        return;
+    }
+
+    let action = bot
+        .send_chat_action(message.chat.id, ChatAction::UploadPhoto)
+        .await;
+    if let Err(e) = action {
+        warn!("Failed to send upload photo action: {:?}", e);
+    }
+
+    match bedrock::generate_images(prompt, &options).await {
+        Ok(images) if images.len() == 1 => {
+            let image = images.into_iter().next().unwrap();
+            bot.send_photo(
+                message.chat.id,
+                InputFile::memory(image).file_name("image.png"),
+            )
+            .reply_parameters(ReplyParameters::new(message.id))
+            .disable_notification(true)
+            .await
+            .unwrap();
+        }
+        Ok(images) => {
+            let media = images.into_iter().enumerate().map(|(i, image)| {
+                InputMedia::Photo(InputMediaPhoto::new(
+                    InputFile::memory(image).file_name(format!("image_{}.png", i + 1)),
+                ))
+            });
+            bot.send_media_group(message.chat.id, media)
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+        }
+        Err(e) => {
+            warn!("Failed to generate image: {}", e);
+            let bot_msg = bot
+                .send_message(
+                    message.chat.id,
+                    messages::image_error(chat_lang(settings), &e),
+                )
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+}
+
+// The cached transcription if there is one, otherwise the audio is transcribed now and cached
+async fn transcript_for(
+    bot: &Bot,
+    message: &Message,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+    unique_file_id: &String,
+    task_type: TaskType,
+) -> Result<String, String> {
+    let cached = get_cached_item(dynamodb, settings, unique_file_id, &task_type).await;
+    if let Ok(ItemReturnInfo::Text(text, _)) = cached {
+        return Ok(text);
+    }
+
+    let (audio_bytes, mime, duration) = download_audio(bot, message)
+        .await
+        .map_err(|e| e.to_string())?;
+    if duration > MAX_DURATION * 60 {
+        return Err(format!("Duration is above {} minutes", MAX_DURATION));
+    }
+
+    let text = match chat_provider(settings)
+        .transcribe(&task_type, audio_bytes, mime, caption_prompt(message))
+        .await
+    {
+        Ok(Some(transcription)) => transcription.text.trim().to_string(),
+        Ok(None) => return Err("No speech found in the audio".to_string()),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    save_to_cache(
+        dynamodb,
+        &cached,
+        unique_file_id,
+        task_type,
+        &text,
+        duration,
+    )
+    .await;
+
+    Ok(text)
+}
+
+// Saves a fresh result the same way process_audio_message does, `cached` is what the lookup returned.
+// CacheDisabled also covers chats that skip the cache.
+async fn save_to_cache(
+    dynamodb: &aws_sdk_dynamodb::Client,
+    cached: &Result<ItemReturnInfo, aws_sdk_dynamodb::Error>,
+    unique_file_id: &String,
+    task_type: TaskType,
+    text: &str,
+    duration: u32,
+) {
+    let res = match cached {
+        Ok(ItemReturnInfo::Exists) => {
+            dynamodb::append_attribute(
+                dynamodb,
+                unique_file_id,
+                &task_type,
+                &text.to_string(),
+                duration,
+            )
+            .await
+        }
+        Ok(ItemReturnInfo::None) => {
+            let item = dynamodb::DBItem {
+                text: text.to_string(),
+                unique_file_id: unique_file_id.clone(),
+                task_type,
+                duration,
+            };
+            dynamodb::add_item(dynamodb, item).await
+        }
+        _ => Ok(()),
+    };
+    if let Err(e) = res {
+        error!("Failed to save {} to DynamoDB: {:?}", task_type, e);
+    }
+}
+
+async fn handle_both_command(
+    bot: &Bot,
+    message: &Message,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    let typing = start_typing_indicator(bot, message.chat.id);
+    let unique_file_id = get_unique_file_id(message).cloned().unwrap_or_default();
+    let res = transcribe_and_translate(bot, message, dynamodb, settings, &unique_file_id).await;
+    drop(typing);
+
+    match res {
+        Ok([transcription, translation]) => {
+            let text = format!("📝 Transcription:\n{transcription}\n\n🇬🇧 English:\n{translation}");
+            let options = send_options(settings, message, message.id);
+            safe_send(bot, message.chat.id, Some(&text), &options, None).await;
+        }
+        Err(e) => {
+            warn!("Failed to transcribe and translate: {}", e);
+            let bot_msg = bot
+                .send_message(message.chat.id, format!("ERROR: {e}"))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+}
+
+// Each task comes from the cache when possible, the audio is only downloaded once if either is missing
+async fn transcribe_and_translate(
+    bot: &Bot,
+    message: &Message,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+    unique_file_id: &String,
+) -> Result<[String; 2], String> {
+    let mut audio: Option<(Bytes, Mime, u32)> = None;
+    let mut texts = [String::new(), String::new()];
+
+    for (text, task_type) in texts
+        .iter_mut()
+        .zip([TaskType::Transcribe, TaskType::Translate])
+    {
+        if let Ok(ItemReturnInfo::Text(cached, _)) =
+            get_cached_item(dynamodb, settings, unique_file_id, &task_type).await
+        {
+            info!("{} found in DynamoDB", task_type);
+            *text = cached;
+            continue;
+        }
+
+        let (audio_bytes, mime, duration) = match &audio {
+            Some(audio) => audio.clone(),
+            None => {
+                let downloaded = download_audio(bot, message)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                if downloaded.2 > MAX_DURATION * 60 {
+                    return Err(format!("Duration is above {} minutes", MAX_DURATION));
+                }
+                audio.insert(downloaded).clone()
+            }
+        };
+
+        *text = chat_provider(settings)
+            .transcribe(&task_type, audio_bytes, mime, caption_prompt(message))
+            .await
+            .map_err(|e| e.to_string())?
+            .map(|transcription| transcription.text.trim().to_string())
+            .unwrap_or("<no text>".to_string());
+
+        if skip_cache(settings) {
+            continue;
+        }
+        if let Err(e) =
+            dynamodb::append_attribute(dynamodb, unique_file_id, &task_type, text, duration).await
+        {
+            error!("Failed to save {} to DynamoDB: {:?}", task_type, e);
+        }
+    }
+
+    Ok(texts)
+}
+
+async fn handle_clean_command(
+    bot: &Bot,
+    message: &Message,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    let typing = start_typing_indicator(bot, message.chat.id);
+    let options = send_options(settings, message, message.id);
+    let unique_file_id = get_unique_file_id(message);
+
+    let cleaned = match (message.text(), unique_file_id) {
+        // One of our transcriptions (or any text), cleaned as it is
+        (Some(text), _) => completion::complete(completion::CLEAN_PROMPT, text)
+            .await
+            .map_err(|e| e.to_string()),
+        (None, Some(unique_file_id)) => {
+            if let Ok(ItemReturnInfo::Text(cleaned, _)) =
+                get_cached_item(dynamodb, settings, unique_file_id, &TaskType::Clean).await
+            {
+                info!("Cleaned transcription found in DynamoDB");
+                drop(typing);
+                safe_send(bot, message.chat.id, Some(&cleaned), &options, None).await;
+                return;
+            }
+
+            match transcript_for(
+                bot,
+                message,
+                dynamodb,
+                settings,
+                unique_file_id,
+                TaskType::Transcribe,
+            )
+            .await
+            {
+                Ok(transcript) => completion::complete(completion::CLEAN_PROMPT, &transcript)
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            }
+        }
+        (None, None) => {
+            drop(typing);
+            bot.send_message(
+                message.chat.id,
+                "Reply to a voice message, video or transcription with /clean.",
+            )
+            .reply_parameters(ReplyParameters::new(message.id))
+            .await
+            .unwrap();
+            return;
+        }
+    };
+    drop(typing);
+
+    match cleaned {
+        Ok(cleaned) => {
+            safe_send(bot, message.chat.id, Some(&cleaned), &options, None).await;
+
+            if let Some(unique_file_id) = unique_file_id.filter(|_| !skip_cache(settings)) {
+                let duration = get_audio_duration(message).unwrap_or_default();
+                if let Err(e) = dynamodb::append_attribute(
+                    dynamodb,
+                    unique_file_id,
+                    &TaskType::Clean,
+                    &cleaned,
+                    duration,
+                )
+                .await
+                {
+                    error!("Failed to save cleaned transcription to DynamoDB: {:?}", e);
+                }
+            }
+        }
+        Err(e) => {
+            warn!("Failed to clean transcription: {}", e);
+            let bot_msg = bot
+                .send_message(message.chat.id, format!("ERROR: {e}"))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+}
+
+// Not cached, the list is short and cheap to regenerate from the cached transcript
+async fn handle_todo_command(
+    bot: &Bot,
+    message: &Message,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    let typing = start_typing_indicator(bot, message.chat.id);
+    let transcript = match (message.text(), get_unique_file_id(message)) {
+        (Some(text), _) => Ok(text.to_string()),
+        (None, Some(unique_file_id)) => {
+            transcript_for(
+                bot,
+                message,
+                dynamodb,
+                settings,
+                unique_file_id,
+                TaskType::Transcribe,
+            )
+            .await
+        }
+        (None, None) => {
+            drop(typing);
+            bot.send_message(
+                message.chat.id,
+                "Reply to a voice message, video or transcription with /todo.",
+            )
+            .reply_parameters(ReplyParameters::new(message.id))
+            .await
+            .unwrap();
+            return;
+        }
+    };
+
+    let todo = match transcript {
+        Ok(transcript) => completion::complete(completion::TODO_PROMPT, &transcript)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    drop(typing);
+
+    match todo {
+        // Sent as plain text, so nothing in the model's output needs escaping
+        Ok(todo) => {
+            let options = send_options(settings, message, message.id);
+            safe_send(bot, message.chat.id, Some(&todo), &options, None).await;
+        }
+        Err(e) => {
+            warn!("Failed to extract action items: {}", e);
+            let bot_msg = bot
+                .send_message(message.chat.id, format!("ERROR: {e}"))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+}
+
+async fn handle_summary_command(
+    bot: &Bot,
+    message: &Message,
+    method: SummarizeMethod,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    let typing = start_typing_indicator(bot, message.chat.id);
+    // A structured summary needs the speaker turns, so audio goes through the diarized transcript
+    let transcript = match (message.text(), get_unique_file_id(message)) {
+        (Some(text), _) => Ok(text.to_string()),
+        (None, Some(unique_file_id)) => {
+            transcript_for(
+                bot,
+                message,
+                dynamodb,
+                settings,
+                unique_file_id,
+                method.task_type(),
+            )
+            .await
+        }
+        (None, None) => {
+            drop(typing);
+            bot.send_message(
+                message.chat.id,
+                "Reply to a voice message, video or transcription with /summary.",
+            )
+            .reply_parameters(ReplyParameters::new(message.id))
+            .await
+            .unwrap();
+            return;
+        }
+    };
+
+    let summary = match transcript {
+        Ok(transcript) => completion::complete(method.prompt(), &transcript)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    drop(typing);
+
+    match summary {
+        Ok(summary) => {
+            let options = send_options(settings, message, message.id);
+            safe_send(bot, message.chat.id, Some(&summary), &options, None).await;
+        }
+        Err(e) => {
+            warn!("Failed to summarize: {}", e);
+            let bot_msg = bot
+                .send_message(message.chat.id, format!("ERROR: {e}"))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+}
+
+async fn handle_json_command(bot: &Bot, message: &Message, settings: &ChatSettings) {
+    let typing = start_typing_indicator(bot, message.chat.id);
+
+    let json = match download_audio(bot, message).await {
+        Ok((_, _, duration)) if duration > MAX_DURATION * 60 => {
+            Err(format!("Duration is above {} minutes", MAX_DURATION))
+        }
+        Ok((audio_bytes, mime, _)) => chat_provider(settings)
+            .transcribe_verbose(
+                &TaskType::Transcribe,
+                audio_bytes,
+                mime,
+                caption_prompt(message),
+            )
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|res| serde_json::to_vec_pretty(&res).map_err(|e| e.to_string())),
+        Err(e) => Err(e.to_string()),
+    };
+    drop(typing);
+
+    match json {
+        Ok(json) => {
+            let file = InputFile::memory(json).file_name("transcription.json");
+            let mut request = bot
+                .send_document(message.chat.id, file)
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true);
+            if let Some(thread_id) = topic_thread(message) {
+                request = request.message_thread_id(thread_id);
+            }
+            request.await.unwrap();
+        }
+        Err(e) => {
+            warn!("Failed to get verbose transcription: {}", e);
+            let bot_msg = bot
+                .send_message(message.chat.id, format!("ERROR: {e}"))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+}
+
+// Segment timings aren't cached, so this always transcribes (like /json and /export)
+async fn handle_stamps_command(bot: &Bot, message: &Message, settings: &ChatSettings) {
+    let typing = start_typing_indicator(bot, message.chat.id);
+
+    let stamped = match download_audio(bot, message).await {
+        Ok((_, _, duration)) if duration > MAX_DURATION * 60 => {
+            Err(format!("Duration is above {} minutes", MAX_DURATION))
+        }
+        Ok((audio_bytes, mime, _)) => chat_provider(settings)
+            .transcribe_verbose(
+                &TaskType::Transcribe,
+                audio_bytes,
+                mime,
+                caption_prompt(message),
+            )
+            .await
+            .map_err(|e| e.to_string())
+            .map(|res| res.to_stamped_text())
+            .and_then(|stamped| {
+                if stamped.is_empty() {
+                    Err("No speech found in the audio".to_string())
+                } else {
+                    Ok(stamped)
+                }
+            }),
+        Err(e) => Err(e.to_string()),
+    };
+    drop(typing);
+
+    match stamped {
+        Ok(stamped) => {
+            let text = with_audio_heading(message, stamped);
+            let options = send_options(settings, message, message.id);
+            safe_send(bot, message.chat.id, Some(&text), &options, None).await;
+        }
+        Err(e) => {
+            warn!("Failed to get timestamped transcription: {}", e);
+            let bot_msg = bot
+                .send_message(message.chat.id, format!("ERROR: {e}"))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+}
+
+async fn handle_feedback_command(
+    bot: &Bot,
+    message: &Message,
+    correction: &str,
+    dynamodb: &aws_sdk_dynamodb::Client,
+) {
+    let me = bot_me(bot).await;
+    let original = message
+        .reply_to_message()
+        .filter(|reply| {
+            reply
+                .from
+                .as_ref()
+                .zip(me)
+                .is_some_and(|(user, me)| user.id == me.id)
+        })
+        .and_then(|reply| reply.text().map(|text| (reply, text)));
+
+    let text = match original {
+        _ if correction.trim().is_empty() => {
+            "Reply to a transcription with /feedback <the corrected text>.".to_string()
+        }
+        None => "Reply to one of my transcriptions to correct it.".to_string(),
+        Some((reply, original)) => {
+            // The transcription replies to the audio unless quoting is off
+            let unique_file_id = reply.reply_to_message().and_then(get_unique_file_id);
+            match dynamodb::add_feedback(
+                dynamodb,
+                message.chat.id,
+                reply.id,
+                unique_file_id.map(String::as_str),
+                original,
+                correction.trim(),
+            )
+            .await
+            {
+                Ok(_) => format!(
+                    "Thanks, the correction was saved. It's kept for {} days.",
+                    dynamodb::cache_ttl_days()
+                ),
+                Err(e) => {
+                    error!("Failed to save feedback: {:?}", e);
+                    "Failed to save the correction, please try again later.".to_string()
+                }
+            }
+        }
+    };
+
+    bot.send_message(message.chat.id, text)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+}
+
+async fn handle_export_command(
+    bot: &Bot,
+    message: &Message,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    let typing = start_typing_indicator(bot, message.chat.id);
+    let archive = export_files(bot, message, dynamodb, settings)
+        .await
+        .and_then(|files| zip_files(&files).map_err(|e| e.to_string()));
+    drop(typing);
+
+    match archive {
+        Ok(archive) => {
+            let file = InputFile::memory(archive).file_name("transcription.zip");
+            let mut request = bot
+                .send_document(message.chat.id, file)
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true);
+            if let Some(thread_id) = topic_thread(message) {
+                request = request.message_thread_id(thread_id);
+            }
+            request.await.unwrap();
+        }
+        Err(e) => {
+            warn!("Failed to export transcription: {}", e);
+            let bot_msg = bot
+                .send_message(message.chat.id, format!("ERROR: {e}"))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+}
+
+// Whatever fails is left out of the archive, it's only an error if nothing worked
+async fn export_files(
+    bot: &Bot,
+    message: &Message,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) -> Result<Vec<(&'static str, String)>, String> {
+    let (audio_bytes, mime, duration) = download_audio(bot, message)
+        .await
+        .map_err(|e| e.to_string())?;
+    if duration > MAX_DURATION * 60 {
+        return Err(format!("Duration is above {} minutes", MAX_DURATION));
+    }
+
+    let provider = chat_provider(settings);
+    let prompt = caption_prompt(message);
+    let unique_file_id = get_unique_file_id(message).cloned().unwrap_or_default();
+    let mut files = Vec::new();
+
+    // The subtitles need the segment timings, which aren't cached, so the SRT always takes a request.
+    // A cached transcript is still preferred, so it matches what the chat was sent before.
+    let cached = get_cached_item(dynamodb, settings, &unique_file_id, &TaskType::Transcribe).await;
+    let mut transcript = match &cached {
+        Ok(ItemReturnInfo::Text(transcript, _)) => Some(transcript.clone()),
+        _ => None,
+    };
+    match provider
+        .transcribe_verbose(
+            &TaskType::Transcribe,
+            audio_bytes.clone(),
+            mime.clone(),
+            prompt,
+        )
+        .await
+    {
+        Ok(res) => {
+            files.push(("subtitles.srt", res.to_srt()));
+            if transcript.is_none() {
+                if let Some(transcription) = transcribe::filter_segments(&TaskType::Transcribe, res)
+                {
+                    let text = transcription.text.trim().to_string();
+                    save_to_cache(
+                        dynamodb,
+                        &cached,
+                        &unique_file_id,
+                        TaskType::Transcribe,
+                        &text,
+                        duration,
+                    )
+                    .await;
+                    transcript = Some(text);
+                }
+            }
+        }
+        Err(e) => warn!("Failed to transcribe audio for the export: {}", e),
+    }
+
+    if let Some(transcript) = transcript {
+        let summary = if completion::enabled() {
+            Some(completion::complete(SummarizeMethod::Plain.prompt(), &transcript).await)
+        } else {
+            None
+        };
+        files.push(("transcript.txt", transcript));
+        match summary {
+            Some(Ok(summary)) => files.push(("summary.txt", summary)),
+            Some(Err(e)) => warn!("Failed to summarize audio for the export: {}", e),
+            None => {}
+        }
+    }
+
+    let cached = get_cached_item(dynamodb, settings, &unique_file_id, &TaskType::Translate).await;
+    let translation = match &cached {
+        Ok(ItemReturnInfo::Text(translation, _)) => Ok(Some(translation.clone())),
+        _ => provider
+            .transcribe(&TaskType::Translate, audio_bytes, mime, prompt)
+            .await
+            .map(|translation| translation.map(|translation| translation.text.trim().to_string())),
+    };
+    match translation {
+        Ok(Some(translation)) => {
+            // Does nothing if it came from the cache
+            save_to_cache(
+                dynamodb,
+                &cached,
+                &unique_file_id,
+                TaskType::Translate,
+                &translation,
+                duration,
+            )
+            .await;
+            files.push(("translation.txt", translation.trim().to_string()));
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to translate audio for the export: {}", e),
+    }
+
+    if files.is_empty() {
+        return Err("Couldn't transcribe the audio, please try again later.".to_string());
+    }
+    Ok(files)
+}
+
+// Private mode keeps the chat's transcriptions out of the cache, in both directions.
+// So does a non-default model, the cache is shared and only holds whisper-large-v3 output.
+fn skip_cache(settings: &ChatSettings) -> bool {
+    settings.enabled(ChatSetting::PrivateMode)
+        || !matches!(chat_model(settings), WhisperModel::Large)
+}
+
+// Like dynamodb::get_item, but acts as if caching was disabled for chats in private mode
+async fn get_cached_item(
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+    unique_file_id: &String,
+    task_type: &TaskType,
+) -> Result<ItemReturnInfo, aws_sdk_dynamodb::Error> {
+    if skip_cache(settings) {
+        return Ok(ItemReturnInfo::CacheDisabled);
+    }
+    dynamodb::get_item(dynamodb, unique_file_id, task_type).await
+}
+
+async fn bot_me(bot: &Bot) -> Option<&'static Me> {
+    match ME.get_or_try_init(|| async { bot.get_me().await }).await {
+        Ok(me) => Some(me),
+        Err(e) => {
+            error!("Failed to get the bot's own user: {:?}", e);
+            None
+        }
+    }
+}
+
+// Without it only "/command@<username>" stops matching, plain "/command" still works
+async fn bot_username(bot: &Bot) -> &'static str {
+    bot_me(bot).await.map_or("", Me::username)
+}
+
+// Read once per update, every handler gets the same copy
+async fn load_settings(
+    dynamodb: &aws_sdk_dynamodb::Client,
+    chat_id: Option<ChatId>,
+) -> ChatSettings {
+    let Some(chat_id) = chat_id else {
+        return ChatSettings::default();
+    };
+
+    match dynamodb::get_settings(dynamodb, chat_id).await {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("Failed to get settings: {:?}", e);
+            ChatSettings::default()
+        }
+    }
+}
+
+async fn handle_setting_command(
+    bot: &Bot,
+    message: &Message,
+    setting: ChatSetting,
+    arg: &str,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    let enabled = match arg.trim().to_lowercase().as_str() {
+        "on" => true,
+        "off" => false,
+        _ => {
+            let status = if settings.enabled(setting) {
+                "on"
+            } else {
+                "off"
+            };
+            let command = setting.command();
+            bot.send_message(
+                message.chat.id,
+                format!(
+                    "{} is {status}. Use /{command} on or /{command} off to change it.",
+                    setting.label()
+                ),
+            )
+            .reply_parameters(ReplyParameters::new(message.id))
+            .await
+            .unwrap();
+            return;
+        }
+    };
+
+    if !is_chat_admin(bot, message).await {
+        let lang = chat_lang(settings);
+        bot.send_message(message.chat.id, messages::text(lang, Msg::AdminOnly))
+            .reply_parameters(ReplyParameters::new(message.id))
+            .await
+            .unwrap();
+        return;
+    }
+
+    let text = match dynamodb::set_setting(dynamodb, message.chat.id, setting, enabled).await {
+        Ok(_) => match (setting, enabled) {
+            (ChatSetting::AutoTranscribe, false) => "Automatic transcription disabled. Reply to a voice message with /transcribe to transcribe it.".to_string(),
+            (ChatSetting::PrivateMode, true) => "Private mode enabled. Transcriptions in this chat are no longer cached, and cached ones are not reused.".to_string(),
+            (_, true) => format!("{} enabled.", setting.label()),
+            (_, false) => format!("{} disabled.", setting.label()),
+        },
+        Err(e) => setting_error(&format!("{setting} setting"), e),
+    };
+
+    bot.send_message(message.chat.id, text)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+}
+
+// The reply when a setting couldn't be saved, `name` is only used in the logs
+fn setting_error(name: &str, err: dynamodb::SettingError) -> String {
+    match err {
+        dynamodb::SettingError::CacheDisabled => {
+            warn!("Can't save {} without DYNAMODB_TABLE", name);
+            "Settings are stored in the cache, which is disabled on this bot. Nothing was saved."
+                .to_string()
+        }
+        dynamodb::SettingError::DynamoDb(e) => {
+            error!("Failed to save {}: {:?}", name, e);
+            "Failed to save the setting, please try again later.".to_string()
+        }
+    }
+}
+
+async fn handle_settings_command(bot: &Bot, message: &Message, settings: &ChatSettings) {
+    let mut lines = vec!["<b>Settings of this chat</b>".to_string()];
+    for setting in ChatSetting::iter() {
+        let value = if settings.enabled(setting) {
+            "on"
+        } else {
+            "off"
+        };
+        lines.push(format!(
+            "{}: {} (/{})",
+            setting.label(),
+            html::code_inline(value),
+            setting.command()
+        ));
+    }
+
+    let lang: Lang = settings
+        .text(TextSetting::Language)
+        .and_then(|lang| lang.parse().ok())
+        .unwrap_or_default();
+    lines.push(format!(
+        "Language: {} (/lang)",
+        html::code_inline(&lang.to_string())
+    ));
+    lines.push(format!(
+        "Model: {} (/model)",
+        html::code_inline(&chat_model(settings).to_string())
+    ));
+    if tts::enabled() {
+        let voice = settings.text(TextSetting::TtsVoice).unwrap_or("random");
+        lines.push(format!("Voice: {} (/voice)", html::code_inline(voice)));
+    }
+
+    bot.send_message(message.chat.id, lines.join("\n"))
+        .parse_mode(ParseMode::Html)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+}
+
+async fn handle_leaderboard_command(
+    bot: &Bot,
+    message: &Message,
+    arg: &str,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+) {
+    // /leaderboard on and /leaderboard off work like the other settings
+    if !arg.trim().is_empty() {
+        handle_setting_command(
+            bot,
+            message,
+            ChatSetting::Leaderboard,
+            arg,
+            dynamodb,
+            settings,
+        )
+        .await;
+        return;
+    }
+
+    let text = if !settings.enabled(ChatSetting::Leaderboard) {
+        "The leaderboard is off. An admin can turn it on with /leaderboard on, only audio sent after that is counted.".to_string()
+    } else {
+        match dynamodb::get_leaderboard(dynamodb, message.chat.id).await {
+            Ok(leaderboard) if leaderboard.is_empty() => {
+                "Nobody has sent any audio since the leaderboard was turned on.".to_string()
+            }
+            Ok(leaderboard) => {
+                let lines: Vec<String> = leaderboard
+                    .iter()
+                    .take(LEADERBOARD_SIZE)
+                    .enumerate()
+                    .map(|(i, (name, seconds))| {
+                        format!(
+                            "{}. {name} — {}",
+                            i + 1,
+                            format_duration(u32::try_from(*seconds).unwrap_or(u32::MAX))
+                        )
+                    })
+                    .collect();
+                format!("🏆 Most transcribed audio\n\n{}", lines.join("\n"))
+            }
+            Err(e) => {
+                error!("Failed to get the leaderboard: {:?}", e);
+                "Failed to get the leaderboard, please try again later.".to_string()
+            }
+        }
+    };
+
+    bot.send_message(message.chat.id, text)
+        .reply_parameters(ReplyParameters::new(message.id))
+        .await
+        .unwrap();
+}
+
+fn duration_footer(settings: &ChatSettings, duration: Option<u32>) -> String {
+    match duration {
+        Some(duration) if settings.enabled(ChatSetting::DurationFooter) => {
+            format!("\n\n⏱ {}", format_duration(duration))
+        }
+        _ => String::new(),
+    }
+}
+
+// MAX_CONCURRENT_TRANSCRIPTIONS limits transcriptions per container, requests over it wait a bit before giving up
+fn transcription_slots() -> &'static Semaphore {
+    TRANSCRIPTION_SLOTS.get_or_init(|| {
+        let limit = env::var("MAX_CONCURRENT_TRANSCRIPTIONS")
+            .ok()
+            .and_then(|limit| limit.trim().parse().ok())
+            .filter(|limit| *limit > 0)
+            .unwrap_or(DEFAULT_CONCURRENT_TRANSCRIPTIONS);
+        Semaphore::new(limit)
+    })
+}
+
+// MIN_DURATION_SECONDS, shorter clips are ignored. 0 transcribes everything
+fn min_duration_seconds() -> u32 {
+    env::var("MIN_DURATION_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.trim().parse().ok())
+        .unwrap_or(DEFAULT_MIN_DURATION_SECONDS)
+}
+
+// Pocket taps and accidental recordings, not worth a reply.
+// Only checked before transcribing automatically, asking for a clip explicitly always works.
+fn below_min_duration(message: &Message) -> bool {
+    get_audio_duration(message).unwrap_or_default() < min_duration_seconds()
+}
+
+// Whether handle_request transcribed the message on its own when it arrived
+fn transcribed_automatically(settings: &ChatSettings, message: &Message) -> bool {
+    settings.enabled(ChatSetting::AutoTranscribe)
+        && !message.is_automatic_forward()
+        && (message.voice().is_some() || message.video_note().is_some())
+        && !below_min_duration(message)
+}
+
+async fn handle_audio_message(
+    message: Message,
+    bot: Bot,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+    task_type: TaskType,
+) -> Result<lambda_http::Response<String>, lambda_http::Error> {
+    let Some(unique_file_id) = get_unique_file_id(&message).cloned() else {
+        unreachable!();
+    };
+    let chat_id = message.chat.id;
+
+    // No point in downloading a file Groq is going to reject anyway
+    if let Some(mime) = unsupported_mime(&message) {
+        warn!("Unsupported mime type {} for {}", mime, unique_file_id);
+        let lang = chat_lang(settings);
+        // Not deleted, the user should know to send the clip in a different format
+        bot.send_message(
+            chat_id,
+            messages::text(lang, Msg::UnsupportedFormat(mime.essence_str())),
+        )
+        .reply_parameters(ReplyParameters::new(message.id))
+        .disable_notification(true)
+        .await
+        .unwrap();
+
+        return Ok(lambda_http::Response::builder()
+            .status(200)
+            .body(String::new())
+            .unwrap());
+    }
+
+    // Each transcription holds the whole file in memory, so a burst could run the container out of it
+    let Ok(Ok(_permit)) = tokio::time::timeout(
+        Duration::from_secs(TRANSCRIPTION_QUEUE_SECONDS),
+        transcription_slots().acquire(),
+    )
+    .await
+    else {
+        warn!(
+            "Too many transcriptions in progress, rejecting {}",
+            unique_file_id
+        );
+        let lang = chat_lang(settings);
+        let bot_msg = bot
+            .send_message(chat_id, messages::text(lang, Msg::Busy))
+            .reply_parameters(ReplyParameters::new(message.id))
+            .disable_notification(true)
+            .await
+            .unwrap();
+
+        delete_message_delay(&bot, &bot_msg, DEFAULT_DELAY).await;
+        return Ok(lambda_http::Response::builder()
+            .status(200)
+            .body(String::new())
+            .unwrap());
+    };
+
+    // Telegram retries the webhook if we're slow, make sure only one invocation handles the clip
+    match dynamodb::acquire_lock(dynamodb, chat_id, &unique_file_id, &task_type).await {
+        Ok(LockStatus::Acquired) => {}
+        Ok(LockStatus::Locked) => {
+            info!(
+                "unique_file_id: {} is already being processed, skipping",
+                unique_file_id
+            );
+            return Ok(lambda_http::Response::builder()
+                .status(200)
+                .body(String::new())
+                .unwrap());
+        }
+        Err(e) => error!("Failed to acquire processing lock: {:?}", e), // process it anyway
+    }
+
+    let res = process_audio_message(message, bot, dynamodb, settings, task_type).await;
+
+    if let Err(e) = dynamodb::release_lock(dynamodb, chat_id, &unique_file_id, &task_type).await {
+        error!("Failed to release processing lock: {:?}", e);
+    }
+
+    res
+}
+
+async fn process_audio_message(
+    message: Message,
+    bot: Bot,
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+    task_type: TaskType,
+) -> Result<lambda_http::Response<String>, lambda_http::Error> {
+    // Used as the cache key. It stays the same when the message is forwarded (see get_unique_file_id)
+    let unique_file_id: &String;
+
+    // Keep showing "typing" until we reply
+    debug!("Starting typing indicator");
+    let typing = start_typing_indicator(&bot, message.chat.id);
+
+    // Check if the message is a voice or video note or just a video
+    if let Some(voice) = message.voice() {
+        let filemeta = &voice.file;
+        unique_file_id = &filemeta.unique_id;
+        info!("Received voice message!");
+    } else if let Some(video_note) = message.video_note() {
+        let filemeta = &video_note.file;
+        unique_file_id = &filemeta.unique_id;
+        info!("Received video note!");
+    } else if let Some(video_file) = message.video() {
+        let filemeta = &video_file.file;
+        unique_file_id = &filemeta.unique_id;
+        info!("Received video message!");
+    } else if let Some(audio_file) = message.audio() {
+        let filemeta = &audio_file.file;
+        unique_file_id = &filemeta.unique_id;
+        info!("Received audio file!");
+    } else {
+        unreachable!();
+    }
+
+    // Get the transcription from DynamoDB
+    let item = get_cached_item(dynamodb, settings, unique_file_id, &task_type).await;
+    let transcription_type = if let Ok(transcription) = item {
+        match transcription {
+            ItemReturnInfo::Text(transcription, cached_duration) => {
+                info!(
+                    "Transcription found in DynamoDB for unique_file_id: {}",
+                    unique_file_id
+                );
+                metrics::cache_lookup(&task_type, true);
+                if let Err(e) = dynamodb::touch_item(dynamodb, unique_file_id).await {
+                    error!("Failed to extend expiration in DynamoDB: {:?}", e);
+                }
+                let reply = format!(
+                    "{}{}",
+                    with_audio_heading(&message, with_task_note(&task_type, &transcription)),
+                    duration_footer(settings, cached_duration)
+                );
+                let reply =
+                    dedupe_reply(dynamodb, settings, message.chat.id, &transcription, reply).await;
+                drop(typing);
+
+                // Send the transcription to the user
+                let options = send_options(settings, &message, message.id);
+                let keyboard = task_keyboard(settings, &options, &task_type, unique_file_id);
+                safe_send(&bot, message.chat.id, Some(&reply), &options, keyboard).await;
+
+                if let Some(duration) = cached_duration.or_else(|| get_audio_duration(&message)) {
+                    record_seconds(dynamodb, settings, &message, &task_type, duration).await;
+                }
+
+                return Ok(lambda_http::Response::builder()
+                    .status(200)
+                    .body(String::new())
+                    .unwrap());
+            }
+            ItemReturnInfo::Exists => {
+                info!(
+                    "Item exists in DynamoDB for unique_file_id: {} but for other task type",
+                    unique_file_id
+                );
+                metrics::cache_lookup(&task_type, false);
+                ItemReturnInfo::Exists
+            }
+            ItemReturnInfo::None => {
+                info!("No items found for unique_file_id: {}", unique_file_id);
+                metrics::cache_lookup(&task_type, false);
+                ItemReturnInfo::None
+            }
+            ItemReturnInfo::CacheDisabled => ItemReturnInfo::CacheDisabled,
+        }
+    } else {
+        error!(
+            "Failed to get item from DynamoDB: {:?}",
+            item.err().unwrap()
+        );
+        ItemReturnInfo::None // if something happens ignore the db
+    };
+
+    // (audio_bytes, mime, duration) = download_audio(&bot, &message).await?;
+    let res = download_audio(&bot, &message).await;
+    let lang = chat_lang(settings);
+    if let Err(e) = res {
+        drop(typing);
+        error!("Failed to download audio: {:?}", e);
+        let bot_msg = bot
+            .send_message(
+                message.chat.id,
+                messages::text(lang, Msg::Error(&e.to_string())),
+            )
+            .reply_parameters(ReplyParameters::new(message.id))
+            .disable_notification(true)
+            .await
+            .unwrap();
+
+        delete_message_delay(&bot, &bot_msg, DEFAULT_DELAY).await;
+
+        return Ok(lambda_http::Response::builder()
+            .status(200)
+            .body(String::new())
+            .unwrap());
+    }
+
+    let (audio_bytes, mime, duration) = res.unwrap();
+
+    // If the duration is above MAX_DURATION
+    if duration > MAX_DURATION * 60 {
+        drop(typing);
+        warn!("The audio message is above {MAX_DURATION} minutes!");
+        bot.send_message(
+            message.chat.id,
+            messages::text(lang, Msg::DurationTooLong(MAX_DURATION)),
+        )
+        .reply_parameters(ReplyParameters::new(message.id))
+        .disable_notification(true)
+        .await
+        .unwrap();
+
+        // we don't want to delete the message
+        // Return early if the audio is too long
+        return Ok(lambda_http::Response::builder()
+            .status(200)
+            .body(String::new())
+            .unwrap());
+    }
+
+    let options = send_options(settings, &message, message.id);
+    let progress = if duration >= PROGRESS_MESSAGE_MINUTES * 60 {
+        send_progress_message(
+            &bot,
+            message.chat.id,
+            messages::text(lang, Msg::Transcribing(duration.div_ceil(60))),
+            &options,
+        )
+        .await
+    } else {
+        None
+    };
+
+    // Transcribe the message
     info!(
         "Transcribing audio! Duration: {} | Mime: {:?}",
         duration, mime
     );
-    let now = std::time::Instant::now();
-    let transcription = transcribe::transcribe(&task_type, audio_bytes, mime).await;
-    info!("Transcribed audio in {}ms", now.elapsed().as_millis());
+    let file_size = audio_bytes.len();
+    let now = Instant::now();
+    let provider = chat_provider(settings);
+    let transcription = provider
+        .transcribe(&task_type, audio_bytes, mime, caption_prompt(&message))
+        .await;
+    let elapsed = now.elapsed().as_millis();
+    drop(typing);
+    info!("Transcribed audio in {}ms", elapsed);
+    if transcription.is_ok() {
+        metrics::transcription(&task_type, elapsed, file_size, duration);
+    } else if let Some(progress) = &progress {
+        // Errors are sent as their own reply, the placeholder would just be left hanging
+        if let Err(e) = bot.delete_message(progress.chat.id, progress.id).await {
+            warn!("Failed to delete progress message: {:?}", e);
+        }
+    }
 
     let transcription = match transcription {
-        Ok(transcription) => transcription,
+        Ok(transcription) => {
+            record_seconds(dynamodb, settings, &message, &task_type, duration).await;
+            transcription
+        }
+        // If there is a rate limit without a known reset time, return NON-200. We want to retry the transcription later.
+        Err(TranscriptionError::RateLimitReached(None)) => {
+            return Ok(lambda_http::Response::builder()
+                .status(429)
+                .body("Rate limit reached".into())
+                .unwrap());
+        }
+        Err(
+            e @ (TranscriptionError::Timeout
+            | TranscriptionError::InvalidAudio
+            | TranscriptionError::EnglishOnlyModel
+            | TranscriptionError::RateLimitReached(Some(_))),
+        ) => {
+            // Not deleted, the user should know to retry or send a different clip
+            bot.send_message(message.chat.id, messages::transcription_error(lang, &e))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            return Ok(lambda_http::Response::builder()
+                .status(200)
+                .body(String::new())
+                .unwrap());
+        }
         Err(e) => {
-            // If there is a rate limit, return NON-200. We want to retry the transcription later.
-            if e.starts_with("Rate limit reached.") {
-                return Ok(lambda_http::Response::builder()
-                    .status(429)
-                    .body("Rate limit reached".into())
-                    .unwrap());
-            }
             warn!("Failed to transcribe audio: {}", e);
             let bot_msg = bot
-                .send_message(message.chat.id, format!("ERROR: {e}"))
+                .send_message(message.chat.id, messages::transcription_error(lang, &e))
                 .reply_parameters(ReplyParameters::new(message.id))
                 .disable_notification(true)
                 .await
@@ -333,20 +2514,34 @@ async fn handle_audio_message(
         }
     };
 
-    // Send the transcription to the user
+    let low_confidence = transcription
+        .as_ref()
+        .is_some_and(|transcription| transcription.is_low_confidence());
     let transcription = transcription
+        .map(|transcription| transcription.text)
         .unwrap_or("<no text>".to_string())
         .trim()
         .to_string();
 
-    // Send the transcription to the user
-    safe_send(&bot, message.chat.id, Some(&transcription), message.id).await;
+    // Send the transcription to the user, with a heads up if the audio was hard to understand
+    let mut reply = with_audio_heading(&message, with_task_note(&task_type, &transcription));
+    if low_confidence {
+        info!("Transcription has low confidence");
+        reply = format!("{}\n\n{reply}", messages::text(lang, Msg::LowConfidence));
+    }
+    reply += &duration_footer(settings, Some(duration));
+    let reply = dedupe_reply(dynamodb, settings, message.chat.id, &transcription, reply).await;
+    let keyboard = task_keyboard(settings, &options, &task_type, unique_file_id);
+    if !finish_progress_message(&bot, progress.as_ref(), &reply, keyboard.clone()).await {
+        safe_send(&bot, message.chat.id, Some(&reply), &options, keyboard).await;
+    }
 
     // Save the transcription to DynamoDB
     let item = dynamodb::DBItem {
         text: transcription.clone(),
         unique_file_id: unique_file_id.clone(),
-        task_type: task_type.to_string(),
+        task_type,
+        duration,
     };
 
     info!(
@@ -360,8 +2555,14 @@ async fn handle_audio_message(
                 "Updating DynamoDB table for unique_file_id: {}",
                 unique_file_id
             );
-            match dynamodb::append_attribute(dynamodb, unique_file_id, &task_type, &transcription)
-                .await
+            match dynamodb::append_attribute(
+                dynamodb,
+                unique_file_id,
+                &task_type,
+                &transcription,
+                duration,
+            )
+            .await
             {
                 Ok(_) => info!("Successfully updated transcription in DynamoDB"),
                 Err(e) => error!("Failed to update transcription in DynamoDB: {:?}", e),
@@ -371,7 +2572,10 @@ async fn handle_audio_message(
             Ok(_) => info!("Successfully saved transcription to DynamoDB"),
             Err(e) => error!("Failed to save transcription to DynamoDB: {:?}", e),
         },
-        ItemReturnInfo::Text(_) => {
+        ItemReturnInfo::CacheDisabled => {
+            info!("Caching is disabled, not saving transcription");
+        }
+        ItemReturnInfo::Text(..) => {
             unreachable!();
         }
     }
@@ -382,116 +2586,514 @@ async fn handle_audio_message(
         .unwrap())
 }
 
+// With /dedupe on, a transcript that repeats the chat's previous one only gets a short note.
+// Private mode wins, the transcript isn't stored then.
+async fn dedupe_reply(
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+    chat_id: ChatId,
+    transcript: &str,
+    reply: String,
+) -> String {
+    if !settings.enabled(ChatSetting::DedupeReplies) || settings.enabled(ChatSetting::PrivateMode) {
+        return reply;
+    }
+
+    match dynamodb::is_repeated_transcript(dynamodb, chat_id, transcript).await {
+        Ok(true) => {
+            info!("Transcript repeats the previous one, sending a short note");
+            messages::text(chat_lang(settings), Msg::SameAsAbove)
+        }
+        Ok(false) => reply,
+        Err(e) => {
+            error!("Failed to check for a repeated transcript: {:?}", e);
+            reply
+        }
+    }
+}
+
+// Counts the audio towards the sender's /leaderboard total, only in chats that turned it on
+async fn record_seconds(
+    dynamodb: &aws_sdk_dynamodb::Client,
+    settings: &ChatSettings,
+    message: &Message,
+    task_type: &TaskType,
+    duration: u32,
+) {
+    // Channel posts don't have a sender
+    let Some(user) = message.from.as_ref() else {
+        return;
+    };
+    if !settings.enabled(ChatSetting::Leaderboard) {
+        return;
+    }
+    // Translations, diarizations and raw output are the same clip again, only count it once
+    if !matches!(task_type, TaskType::Transcribe) {
+        return;
+    }
+
+    if let Err(e) = dynamodb::add_user_seconds(dynamodb, message.chat.id, user, duration).await {
+        error!("Failed to update the leaderboard: {:?}", e);
+    }
+}
+
+// Only part of the clip is transcribed, so the result isn't cached
+async fn handle_range_command(
+    bot: &Bot,
+    message: &Message,
+    reply: &Message,
+    range: &str,
+    settings: &ChatSettings,
+) -> Result<lambda_http::Response<String>, lambda_http::Error> {
+    let lang = chat_lang(settings);
+    let typing = start_typing_indicator(bot, message.chat.id);
+
+    let res = match parse_time_range(range) {
+        Some((start, end)) => transcribe_range(bot, reply, settings, start, end).await,
+        None => Err(
+            "Use a range like /transcribe 10:00-12:00 (minutes:seconds, start before end)."
+                .to_string(),
+        ),
+    };
+    drop(typing);
+
+    let text = match res {
+        Ok(text) => text,
+        Err(e) => {
+            let bot_msg = bot
+                .send_message(message.chat.id, messages::text(lang, Msg::Error(&e)))
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+            return Ok(lambda_http::Response::builder()
+                .status(200)
+                .body(String::new())
+                .unwrap());
+        }
+    };
+
+    let options = send_options(settings, message, reply.id);
+    safe_send(bot, message.chat.id, Some(&text), &options, None).await;
+
+    Ok(lambda_http::Response::builder()
+        .status(200)
+        .body(String::new())
+        .unwrap())
+}
+
+async fn transcribe_range(
+    bot: &Bot,
+    message: &Message,
+    settings: &ChatSettings,
+    start: u32,
+    end: u32,
+) -> Result<String, String> {
+    let (audio_bytes, mime, duration) = download_audio(bot, message)
+        .await
+        .map_err(|e| e.to_string())?;
+    if end > duration {
+        return Err(format!(
+            "The clip is only {} long.",
+            format_duration(duration)
+        ));
+    }
+    if end - start > MAX_DURATION * 60 {
+        return Err(format!("The range is above {MAX_DURATION} minutes."));
+    }
+
+    // Without ffmpeg the whole clip is sent, with a note so the user isn't confused
+    let (audio_bytes, mime, heading) = match transcode::trim(&audio_bytes, start, end).await {
+        Some(trimmed) => (
+            Bytes::from(trimmed),
+            Mime::from_str("audio/ogg").unwrap(),
+            format!("{}–{}", format_duration(start), format_duration(end)),
+        ),
+        None if duration > MAX_DURATION * 60 => {
+            return Err(format!(
+                "Can't cut the clip right now and it's above {MAX_DURATION} minutes."
+            ));
+        }
+        None => (
+            audio_bytes,
+            mime,
+            "Couldn't cut the clip, this is the whole transcription.".to_string(),
+        ),
+    };
+
+    let transcription = chat_provider(settings)
+        .transcribe(
+            &TaskType::Transcribe,
+            audio_bytes,
+            mime,
+            caption_prompt(message),
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+    let text = transcription
+        .map(|transcription| transcription.text.trim().to_string())
+        .unwrap_or("<no text>".to_string());
+
+    Ok(format!("{heading}\n\n{text}"))
+}
+
+// Linked files aren't cached, the content behind a url can change
+async fn handle_url_message(
+    bot: &Bot,
+    message: &Message,
+    url: reqwest::Url,
+    mime: Mime,
+    settings: &ChatSettings,
+) -> Result<lambda_http::Response<String>, lambda_http::Error> {
+    info!("Received link to audio file: {}", url);
+    let typing = start_typing_indicator(bot, message.chat.id);
+
+    let transcription = match remote_audio::download(&url).await {
+        Ok(audio_bytes) => {
+            let mime = resolve_mime(&audio_bytes, mime);
+            chat_provider(settings)
+                .transcribe(&TaskType::Transcribe, audio_bytes.into(), mime, None)
+                .await
+        }
+        Err(e) => {
+            drop(typing);
+            warn!("Failed to download linked audio: {}", e);
+            let bot_msg = bot
+                .send_message(
+                    message.chat.id,
+                    messages::text(chat_lang(settings), Msg::LinkDownloadFailed),
+                )
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+            return Ok(lambda_http::Response::builder()
+                .status(200)
+                .body(String::new())
+                .unwrap());
+        }
+    };
+    drop(typing);
+
+    match transcription {
+        Ok(transcription) => {
+            let text = transcription.map(|transcription| transcription.text);
+            let options = send_options(settings, message, message.id);
+            safe_send(bot, message.chat.id, text.as_deref(), &options, None).await;
+        }
+        Err(TranscriptionError::RateLimitReached(None)) => {
+            return Ok(lambda_http::Response::builder()
+                .status(429)
+                .body("Rate limit reached".into())
+                .unwrap());
+        }
+        Err(e) => {
+            warn!("Failed to transcribe linked audio: {}", e);
+            let bot_msg = bot
+                .send_message(
+                    message.chat.id,
+                    messages::transcription_error(chat_lang(settings), &e),
+                )
+                .reply_parameters(ReplyParameters::new(message.id))
+                .disable_notification(true)
+                .await
+                .unwrap();
+
+            delete_message_delay(bot, &bot_msg, DEFAULT_DELAY).await;
+        }
+    }
+
+    Ok(lambda_http::Response::builder()
+        .status(200)
+        .body(String::new())
+        .unwrap())
+}
+
+// Explains the output format when it isn't obvious
+fn with_task_note(task_type: &TaskType, text: &str) -> String {
+    match task_type {
+        TaskType::Diarize => format!(
+            "Speaker changes (—) are guessed from pauses in the audio, this is not real speaker detection.\n\n{text}"
+        ),
+        TaskType::Raw => format!(
+            "Unfiltered transcription, it may contain filler Whisper made up during silence.\n\n{text}"
+        ),
+        TaskType::Transcribe | TaskType::Translate | TaskType::Clean => text.to_string(),
+    }
+}
+
+// Audio files can carry a title and performer, which helps tell them apart in the chat
+fn with_audio_heading(message: &Message, text: String) -> String {
+    match audio_heading(message) {
+        Some(heading) => format!("{heading}\n\n{text}"),
+        None => text,
+    }
+}
+
+// Follow-up actions offered under the reply
+// The callback data is "<action>:<unique_file_id>", so the action can go straight to the cache
+fn task_keyboard(
+    settings: &ChatSettings,
+    options: &SendOptions,
+    task_type: &TaskType,
+    unique_file_id: &str,
+) -> Option<InlineKeyboardMarkup> {
+    // The English-only model can't translate, the button could only fail
+    if chat_model(settings).english_only() {
+        return None;
+    }
+    // On a cache miss the button finds the audio through our reply, an unquoted reply loses it
+    options.reply_to?;
+
+    match task_type {
+        TaskType::Transcribe => Some(InlineKeyboardMarkup::new([[
+            InlineKeyboardButton::callback(
+                "Translate to English",
+                format!("translate:{unique_file_id}"),
+            ),
+        ]])),
+        TaskType::Translate | TaskType::Diarize | TaskType::Raw | TaskType::Clean => None,
+    }
+}
+
+// How transcriptions are sent, from the chat's settings
+struct SendOptions {
+    reply_to: Option<MessageId>,
+    thread_id: Option<ThreadId>,
+    silent: bool,
+}
+
+fn send_options(settings: &ChatSettings, message: &Message, reply_to: MessageId) -> SendOptions {
+    SendOptions {
+        // Comments under a channel post can only be answered in place by replying to them
+        reply_to: (settings.enabled(ChatSetting::ReplyQuoting) || is_comment(message))
+            .then_some(reply_to),
+        thread_id: topic_thread(message),
+        silent: settings.enabled(ChatSetting::SilentReplies),
+    }
+}
+
+// A message in a channel's discussion group, posted under one of the channel's posts
+fn is_comment(message: &Message) -> bool {
+    message
+        .reply_to_message()
+        .is_some_and(|reply| reply.is_automatic_forward())
+}
+
+// In forum groups replies have to name their topic, otherwise they can end up in General.
+// Outside of forums the thread id is just the reply chain, so it's left unset there.
+fn topic_thread(message: &Message) -> Option<ThreadId> {
+    message.thread_id.filter(|_| message.is_topic_message)
+}
+
 async fn safe_send(
     bot: &Bot,
     chat_id: ChatId,
     transcription: Option<&str>,
-    reply_message: MessageId,
+    options: &SendOptions,
+    reply_markup: Option<InlineKeyboardMarkup>,
 ) {
     // Send the transcription to the user
     let transcription = transcription.unwrap_or("<no text>").trim().to_string();
 
     // Check the transcription length
-    if transcription.len() > 4096 {
+    let parts = if transcription.len() > 4096 {
         info!("Transcription is too long, splitting into multiple messages");
-        let parts = split_string(&transcription, 4096);
-        for part in parts {
-            bot.send_message(chat_id, &part)
-                .reply_parameters(ReplyParameters::new(reply_message))
-                .disable_notification(true)
-                .await
-                .unwrap();
-        }
+        split_string(&transcription, 4096)
     } else {
-        bot.send_message(chat_id, &transcription)
-            .reply_parameters(ReplyParameters::new(reply_message))
-            .disable_notification(true)
-            .await
-            .unwrap();
+        vec![transcription]
+    };
+
+    let last = parts.len() - 1;
+    for (i, part) in parts.into_iter().enumerate() {
+        let mut request = bot
+            .send_message(chat_id, part)
+            .disable_notification(options.silent);
+        if let Some(reply_to) = options.reply_to {
+            request = request.reply_parameters(ReplyParameters::new(reply_to));
+        }
+        if let Some(thread_id) = options.thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        // Only the last part gets the buttons
+        if i == last {
+            if let Some(markup) = reply_markup.clone() {
+                request = request.reply_markup(markup);
+            }
+        }
+        request.await.unwrap();
     }
 }
 
-async fn download_audio(bot: &Bot, message: &Message) -> Result<(Vec<u8>, Mime, u32), Error> {
-    let mut audio_bytes = Vec::new();
-    let mime;
-    let duration;
+// A placeholder for long clips, so there's more feedback than "typing" while waiting
+async fn send_progress_message(
+    bot: &Bot,
+    chat_id: ChatId,
+    text: String,
+    options: &SendOptions,
+) -> Option<Message> {
+    let mut request = bot.send_message(chat_id, text).disable_notification(true);
+    if let Some(reply_to) = options.reply_to {
+        request = request.reply_parameters(ReplyParameters::new(reply_to));
+    }
+    if let Some(thread_id) = options.thread_id {
+        request = request.message_thread_id(thread_id);
+    }
 
-    if let Some(voice) = message.voice() {
-        let file = bot.get_file(&voice.file.id).await?;
-        if file.size > MAX_FILE_SIZE * 1024 * 1024 {
-            return Err(Error::from(format!(
-                "File can't be larger than {MAX_FILE_SIZE}MB (current size: {}MB)",
-                file.size / 1024 / 1024
-            )));
+    match request.await {
+        Ok(progress) => Some(progress),
+        Err(e) => {
+            warn!("Failed to send progress message: {:?}", e);
+            None
         }
-        info!(
-            "File size: {} bytes ({}MB)",
-            file.size,
+    }
+}
+
+// Edits the placeholder into the result. Returns false if the result still has to be sent,
+// e.g. because it needs several messages or the placeholder was deleted in the meantime.
+async fn finish_progress_message(
+    bot: &Bot,
+    progress: Option<&Message>,
+    text: &str,
+    reply_markup: Option<InlineKeyboardMarkup>,
+) -> bool {
+    let Some(progress) = progress else {
+        return false;
+    };
+
+    let text = text.trim();
+    if text.is_empty() || text.len() > 4096 {
+        if let Err(e) = bot.delete_message(progress.chat.id, progress.id).await {
+            warn!("Failed to delete progress message: {:?}", e);
+        }
+        return false;
+    }
+
+    let mut request = bot.edit_message_text(progress.chat.id, progress.id, text);
+    if let Some(markup) = reply_markup {
+        request = request.reply_markup(markup);
+    }
+
+    match request.await {
+        Ok(_) => true,
+        Err(e) => {
+            warn!(
+                "Failed to edit progress message, sending a new one: {:?}",
+                e
+            );
+            false
+        }
+    }
+}
+
+async fn download_file(bot: &Bot, file_id: &str) -> Result<Vec<u8>, Error> {
+    let file = bot.get_file(file_id).await?;
+    if file.size > MAX_FILE_SIZE * 1024 * 1024 {
+        return Err(Error::from(format!(
+            "File can't be larger than {MAX_FILE_SIZE}MB (current size: {}MB)",
             file.size / 1024 / 1024
+        )));
+    }
+    info!(
+        "File size: {} bytes ({}MB)",
+        file.size,
+        file.size / 1024 / 1024
+    );
+
+    let mut bytes = Vec::new();
+    bot.download_file(&file.path, &mut bytes).await?;
+
+    // A cut off download would otherwise end up as a confusing error from the Whisper API
+    if bytes.len() != file.size as usize {
+        warn!(
+            "Downloaded {} bytes, but Telegram reported {} bytes",
+            bytes.len(),
+            file.size
         );
-        mime = voice
-            .mime_type
-            .clone()
-            .unwrap_or_else(|| Mime::from_str("audio/ogg").unwrap());
+        return Err(Error::from("Download incomplete, please retry."));
+    }
+
+    Ok(bytes)
+}
+
+async fn download_audio(bot: &Bot, message: &Message) -> Result<(Bytes, Mime, u32), Error> {
+    let audio_bytes;
+    let mime;
+    let duration;
+
+    if let Some(voice) = message.voice() {
+        audio_bytes = download_file(bot, &voice.file.id).await?;
+        mime = source_mime(message).unwrap();
         duration = voice.duration;
-        bot.download_file(&file.path, &mut audio_bytes).await?;
     } else if let Some(video_note) = message.video_note() {
-        let file = bot.get_file(&video_note.file.id).await?;
-        if file.size > MAX_FILE_SIZE * 1024 * 1024 {
-            return Err(Error::from(format!(
-                "File can't be larger than {MAX_FILE_SIZE}MB (current size: {}MB)",
-                file.size / 1024 / 1024
-            )));
-        }
-        info!(
-            "File size: {} bytes ({}MB)",
-            file.size,
-            file.size / 1024 / 1024
-        );
-        mime = Mime::from_str("video/mp4").unwrap();
+        // Round videos can be several MB in HD, the audio track is a fraction of that
+        (audio_bytes, mime) = audio_track(
+            download_file(bot, &video_note.file.id).await?,
+            source_mime(message).unwrap(),
+        )
+        .await;
         duration = video_note.duration;
-        bot.download_file(&file.path, &mut audio_bytes).await?;
     } else if let Some(video_file) = message.video() {
-        let file = bot.get_file(&video_file.file.id).await?;
-        if file.size > MAX_FILE_SIZE * 1024 * 1024 {
-            return Err(Error::from(format!(
-                "File can't be larger than {MAX_FILE_SIZE}MB (current size: {}MB)",
-                file.size / 1024 / 1024
-            )));
-        }
-        info!(
-            "File size: {} bytes ({}MB)",
-            file.size,
-            file.size / 1024 / 1024
-        );
-        mime = video_file
-            .mime_type
-            .clone()
-            .unwrap_or_else(|| Mime::from_str("video/mp4").unwrap());
+        (audio_bytes, mime) = audio_track(
+            download_file(bot, &video_file.file.id).await?,
+            source_mime(message).unwrap(),
+        )
+        .await;
         duration = video_file.duration;
-        bot.download_file(&file.path, &mut audio_bytes).await?;
+    } else if let Some(audio_file) = message.audio() {
+        audio_bytes = download_file(bot, &audio_file.file.id).await?;
+        mime = source_mime(message).unwrap();
+        duration = audio_file.duration;
     } else {
         return Err(Error::from("Unsupported message type"));
     }
 
-    const MIME_TYPES: &[&str] = &[
-        "audio/mpeg",
-        "video/mp4",
-        "video/mpeg",
-        "audio/ogg",
-        "audio/mp4",
-        "audio/wav",
-        "video/webm",
-    ];
+    let mime = resolve_mime(&audio_bytes, mime);
+
+    let (audio_bytes, mime) = if transcode::normalization_enabled() {
+        match transcode::normalize_loudness(&audio_bytes).await {
+            Some(normalized) => (normalized, Mime::from_str("audio/ogg").unwrap()),
+            None => (audio_bytes, mime),
+        }
+    } else {
+        (audio_bytes, mime)
+    };
 
-    if !MIME_TYPES.contains(&mime.essence_str()) {
+    if !SUPPORTED_MIME_TYPES.contains(&mime.essence_str()) {
         return Err(Error::from(format!(
             "Unsupported mime type: {}. Supported types: {:?}",
-            mime, MIME_TYPES
+            mime, SUPPORTED_MIME_TYPES
         )));
     }
 
-    Ok((audio_bytes, mime, duration.seconds()))
+    Ok((audio_bytes.into(), mime, duration.seconds()))
+}
+
+// The declared type of audio that can't be transcribed, checked before downloading it.
+// Anything going through ffmpeg (videos, or everything with NORMALIZE_AUDIO) may still be converted.
+fn unsupported_mime(message: &Message) -> Option<Mime> {
+    if transcode::normalization_enabled() {
+        return None;
+    }
+    audio_mime(message).filter(|mime| !SUPPORTED_MIME_TYPES.contains(&mime.essence_str()))
+}
+
+// Only the audio track matters, so if ffmpeg is available upload just that to Groq.
+// Falls back to the whole video (and its mime) without ffmpeg.
+async fn audio_track(video: Vec<u8>, mime: Mime) -> (Vec<u8>, Mime) {
+    // Normalization drops the video track as well, no need to run ffmpeg twice
+    if transcode::normalization_enabled() {
+        return (video, mime);
+    }
+
+    match transcode::extract_audio(&video).await {
+        Some(audio) => (audio, Mime::from_str("audio/ogg").unwrap()),
+        None => (video, mime),
+    }
 }
 
 pub async fn parse_webhook(input: Request) -> Result<Update, Error> {
@@ -503,3 +3105,106 @@ pub async fn parse_webhook(input: Request) -> Result<Update, Error> {
     let body_json: Update = serde_json::from_str(body_str)?;
     Ok(body_json)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse_fixture(fixture: &str) -> Message {
+        let request = Request::new(Body::Text(fixture.to_string()));
+        let update = parse_webhook(request).await.unwrap();
+        match update.kind {
+            UpdateKind::Message(message) => message,
+            kind => panic!("expected UpdateKind::Message, got {kind:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn parse_voice_message() {
+        let message = parse_fixture(include_str!("../tests/fixtures/voice_message.json")).await;
+
+        assert!(message.voice().is_some());
+        assert_eq!(
+            get_unique_file_id(&message).map(String::as_str),
+            Some("AgADvoice1")
+        );
+        assert_eq!(get_audio_duration(&message), Some(7));
+        // Voice messages use the type Telegram reports
+        assert_eq!(
+            audio_mime(&message),
+            Some(Mime::from_str("audio/ogg").unwrap())
+        );
+        assert_eq!(
+            source_mime(&message),
+            Some(Mime::from_str("audio/ogg").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_video_note() {
+        let message = parse_fixture(include_str!("../tests/fixtures/video_note.json")).await;
+
+        assert!(message.video_note().is_some());
+        assert_eq!(
+            get_unique_file_id(&message).map(String::as_str),
+            Some("AgADnote1")
+        );
+        assert_eq!(get_audio_duration(&message), Some(12));
+        // The audio track is extracted later, there's no audio type up front
+        assert_eq!(audio_mime(&message), None);
+        // Video notes don't report a type at all
+        assert_eq!(
+            source_mime(&message),
+            Some(Mime::from_str("video/mp4").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_video() {
+        let message = parse_fixture(include_str!("../tests/fixtures/video.json")).await;
+
+        assert!(message.video().is_some());
+        assert_eq!(
+            get_unique_file_id(&message).map(String::as_str),
+            Some("AgADvideo1")
+        );
+        assert_eq!(get_audio_duration(&message), Some(95));
+        assert_eq!(audio_mime(&message), None);
+        assert_eq!(
+            source_mime(&message),
+            Some(Mime::from_str("video/mp4").unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn parse_audio_file() {
+        let message = parse_fixture(include_str!("../tests/fixtures/audio.json")).await;
+
+        assert!(message.audio().is_some());
+        assert_eq!(
+            get_unique_file_id(&message).map(String::as_str),
+            Some("AgADaudio1")
+        );
+        assert_eq!(get_audio_duration(&message), Some(184));
+        // audio/x-m4a is an alias, it's uploaded as audio/mp4
+        assert_eq!(
+            audio_mime(&message),
+            Some(Mime::from_str("audio/mp4").unwrap())
+        );
+        assert_eq!(source_mime(&message), audio_mime(&message));
+        assert_eq!(caption_prompt(&message), Some("Standup notes"));
+    }
+
+    #[tokio::test]
+    async fn parse_command() {
+        let message = parse_fixture(include_str!("../tests/fixtures/command.json")).await;
+
+        assert_eq!(get_unique_file_id(&message), None);
+        assert_eq!(get_audio_duration(&message), None);
+        assert_eq!(audio_mime(&message), None);
+        assert!(matches!(
+            BotCommand::parse(message.text().unwrap(), "duck_transcriber_bot"),
+            Ok(BotCommand::Transcribe(_))
+        ));
+    }
+}
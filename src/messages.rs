@@ -0,0 +1,202 @@
+// User-facing texts in every supported language. Command descriptions and developer commands stay in English.
+
+use crate::bedrock::BedrockError;
+use crate::bedrock::MAX_IMAGES;
+use crate::transcribe::TranscriptionError;
+use crate::tts::TtsError;
+use crate::tts::MAX_INPUT_LENGTH;
+use crate::utils::format_duration;
+
+#[derive(strum::Display, strum::EnumString, strum::EnumIter, Clone, Copy, Default)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Lang {
+    #[default]
+    En,
+    Pl,
+}
+
+impl Lang {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lang::En => "English",
+            Lang::Pl => "Polski",
+        }
+    }
+}
+
+pub enum Msg<'a> {
+    Welcome,
+    Error(&'a str),
+    DurationTooLong(u32), // in minutes
+    LowConfidence,
+    AdminOnly,
+    LanguageSet,
+    LanguageUsage(Lang),
+    Busy,
+    Transcribing(u32), // in minutes
+    UnsupportedFormat(&'a str),
+    SameAsAbove,
+    AudioNotFound,
+    RateLimited(u64), // seconds until the limit resets
+    TranscriptionTimeout,
+    InvalidAudio,
+    EnglishOnlyModel,
+    LinkDownloadFailed,
+    TtsNotConfigured,
+    TtsTooLong,
+    TtsFailed,
+    ImageSizeUnsupported(u32, u32),
+    ImageCountInvalid,
+    ImageRejected,
+    ImageFailed,
+}
+
+pub fn text(lang: Lang, msg: Msg) -> String {
+    match (lang, msg) {
+        (Lang::En, Msg::Welcome) => "Welcome! Send a voice message or video note to transcribe it. You can also use /help to see all available commands.".to_string(),
+        (Lang::Pl, Msg::Welcome) => "Witaj! Wyślij wiadomość głosową lub wideo, żeby ją przepisać. Użyj /help, żeby zobaczyć wszystkie komendy.".to_string(),
+
+        (Lang::En, Msg::Error(e)) => format!("ERROR: {e}"),
+        (Lang::Pl, Msg::Error(e)) => format!("BŁĄD: {e}"),
+
+        (Lang::En, Msg::DurationTooLong(minutes)) => format!("Duration is above {minutes} minutes"),
+        (Lang::Pl, Msg::DurationTooLong(minutes)) => format!("Nagranie jest dłuższe niż {minutes} minut"),
+
+        (Lang::En, Msg::LowConfidence) => "⚠️ low confidence".to_string(),
+        (Lang::Pl, Msg::LowConfidence) => "⚠️ niska pewność".to_string(),
+
+        (Lang::En, Msg::AdminOnly) => "Only chat admins can change this setting.".to_string(),
+        (Lang::Pl, Msg::AdminOnly) => "Tylko administratorzy czatu mogą zmienić to ustawienie.".to_string(),
+
+        (Lang::En, Msg::LanguageSet) => "The bot will now reply in English.".to_string(),
+        (Lang::Pl, Msg::LanguageSet) => "Bot będzie teraz odpowiadał po polsku.".to_string(),
+
+        (Lang::En, Msg::LanguageUsage(current)) => format!(
+            "The bot's language is {}. Use /lang <code> to change it ({}).",
+            current.name(),
+            codes()
+        ),
+        (Lang::Pl, Msg::LanguageUsage(current)) => format!(
+            "Język bota to {}. Użyj /lang <kod>, żeby go zmienić ({}).",
+            current.name(),
+            codes()
+        ),
+
+        (Lang::En, Msg::Busy) => "Too many transcriptions right now, please try again in a minute.".to_string(),
+        (Lang::Pl, Msg::Busy) => "Za dużo transkrypcji naraz, spróbuj ponownie za minutę.".to_string(),
+
+        (Lang::En, Msg::Transcribing(minutes)) => format!("Transcribing your {minutes}-minute clip, this may take a moment…"),
+        (Lang::Pl, Msg::Transcribing(minutes)) => format!("Przepisuję twoje {minutes}-minutowe nagranie, to może chwilę potrwać…"),
+
+        (Lang::En, Msg::UnsupportedFormat(mime)) => format!("Unsupported format ({mime}). Please send it as mp3, m4a, ogg, wav, flac or a video."),
+        (Lang::Pl, Msg::UnsupportedFormat(mime)) => format!("Nieobsługiwany format ({mime}). Wyślij nagranie jako mp3, m4a, ogg, wav, flac albo wideo."),
+
+        (Lang::En, Msg::SameAsAbove) => "🔁 Same as the transcription above.".to_string(),
+        (Lang::Pl, Msg::SameAsAbove) => "🔁 To samo co transkrypcja wyżej.".to_string(),
+
+        (Lang::En, Msg::AudioNotFound) => "Can't find the original audio anymore, please send it again.".to_string(),
+        (Lang::Pl, Msg::AudioNotFound) => "Nie mogę już znaleźć oryginalnego nagrania, wyślij je ponownie.".to_string(),
+
+        (Lang::En, Msg::RateLimited(seconds)) => format!("Rate limit reached, try again in {}.", wait(seconds)),
+        (Lang::Pl, Msg::RateLimited(seconds)) => format!("Osiągnięto limit zapytań, spróbuj ponownie za {}.", wait(seconds)),
+
+        (Lang::En, Msg::TranscriptionTimeout) => "Transcription timed out. Try a shorter clip or retry in a moment.".to_string(),
+        (Lang::Pl, Msg::TranscriptionTimeout) => "Transkrypcja trwała za długo. Spróbuj krótszego nagrania albo ponów za chwilę.".to_string(),
+
+        (Lang::En, Msg::InvalidAudio) => "The audio was too short or couldn't be read.".to_string(),
+        (Lang::Pl, Msg::InvalidAudio) => "Nagranie było za krótkie albo nie dało się go odczytać.".to_string(),
+
+        (Lang::En, Msg::EnglishOnlyModel) => "This chat uses the English-only model, which can't translate. Switch back with /model large.".to_string(),
+        (Lang::Pl, Msg::EnglishOnlyModel) => "Ten czat używa modelu tylko dla angielskiego, który nie tłumaczy. Wróć do poprzedniego przez /model large.".to_string(),
+
+        (Lang::En, Msg::LinkDownloadFailed) => "Couldn't download the linked file.".to_string(),
+        (Lang::Pl, Msg::LinkDownloadFailed) => "Nie udało się pobrać pliku z linku.".to_string(),
+
+        (Lang::En, Msg::TtsNotConfigured) => "Text to speech is not available.".to_string(),
+        (Lang::Pl, Msg::TtsNotConfigured) => "Zamiana tekstu na mowę jest niedostępna.".to_string(),
+
+        (Lang::En, Msg::TtsTooLong) => format!("Text can't be longer than {MAX_INPUT_LENGTH} characters."),
+        (Lang::Pl, Msg::TtsTooLong) => format!("Tekst nie może być dłuższy niż {MAX_INPUT_LENGTH} znaków."),
+
+        (Lang::En, Msg::TtsFailed) => "Couldn't generate the speech, please try again later.".to_string(),
+        (Lang::Pl, Msg::TtsFailed) => "Nie udało się wygenerować mowy, spróbuj ponownie później.".to_string(),
+
+        (Lang::En, Msg::ImageSizeUnsupported(width, height)) => format!("{width}x{height} is not a supported size. Try 512x512, 768x768, 1024x1024, 1152x768 or 768x1152."),
+        (Lang::Pl, Msg::ImageSizeUnsupported(width, height)) => format!("Rozmiar {width}x{height} nie jest obsługiwany. Spróbuj 512x512, 768x768, 1024x1024, 1152x768 albo 768x1152."),
+
+        (Lang::En, Msg::ImageCountInvalid) => format!("Between 1 and {MAX_IMAGES} images can be generated at once."),
+        (Lang::Pl, Msg::ImageCountInvalid) => format!("Naraz można wygenerować od 1 do {MAX_IMAGES} obrazów."),
+
+        (Lang::En, Msg::ImageRejected) => "The image couldn't be generated, try rephrasing the prompt.".to_string(),
+        (Lang::Pl, Msg::ImageRejected) => "Nie udało się wygenerować obrazu, spróbuj inaczej sformułować opis.".to_string(),
+
+        (Lang::En, Msg::ImageFailed) => "Couldn't generate the image, please try again later.".to_string(),
+        (Lang::Pl, Msg::ImageFailed) => "Nie udało się wygenerować obrazu, spróbuj ponownie później.".to_string(),
+    }
+}
+
+/// The errors users can do something about get their own text, the rest are shown as they are
+pub fn transcription_error(lang: Lang, err: &TranscriptionError) -> String {
+    match err {
+        TranscriptionError::RateLimitReached(Some(seconds)) => {
+            text(lang, Msg::RateLimited(*seconds))
+        }
+        TranscriptionError::Timeout => text(lang, Msg::TranscriptionTimeout),
+        TranscriptionError::InvalidAudio => text(lang, Msg::InvalidAudio),
+        TranscriptionError::EnglishOnlyModel => text(lang, Msg::EnglishOnlyModel),
+        err => text(lang, Msg::Error(&err.to_string())),
+    }
+}
+
+pub fn tts_error(lang: Lang, err: &TtsError) -> String {
+    match err {
+        TtsError::NotConfigured => text(lang, Msg::TtsNotConfigured),
+        TtsError::TooLong => text(lang, Msg::TtsTooLong),
+        TtsError::RequestError(_) | TtsError::ApiError(_) => text(lang, Msg::TtsFailed),
+    }
+}
+
+pub fn image_error(lang: Lang, err: &BedrockError) -> String {
+    match err {
+        BedrockError::UnsupportedSize(width, height) => {
+            text(lang, Msg::ImageSizeUnsupported(*width, *height))
+        }
+        BedrockError::InvalidCount => text(lang, Msg::ImageCountInvalid),
+        BedrockError::Rejected => text(lang, Msg::ImageRejected),
+        BedrockError::RequestError(_) | BedrockError::ParseError(_) => text(lang, Msg::ImageFailed),
+    }
+}
+
+fn wait(seconds: u64) -> String {
+    format_duration(u32::try_from(seconds).unwrap_or(u32::MAX))
+}
+
+fn codes() -> String {
+    use strum::IntoEnumIterator;
+
+    Lang::iter()
+        .map(|lang| format!("{lang} = {}", lang.name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transcription_errors_are_localized() {
+        assert_eq!(
+            transcription_error(Lang::Pl, &TranscriptionError::RateLimitReached(Some(90))),
+            "Osiągnięto limit zapytań, spróbuj ponownie za 1:30."
+        );
+        assert_eq!(
+            transcription_error(Lang::En, &TranscriptionError::Timeout),
+            TranscriptionError::Timeout.to_string()
+        );
+        assert_eq!(
+            transcription_error(Lang::Pl, &TranscriptionError::ApiError("500".to_string())),
+            "BŁĄD: Whisper API returned an error: 500"
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use serde_json::{json, Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::transcribe::TaskType;
+
+// CloudWatch creates the metrics automatically from these log lines (Embedded Metric Format)
+const NAMESPACE: &str = "DuckTranscriber";
+
+pub enum Unit {
+    Milliseconds,
+    Bytes,
+    Seconds,
+    Count,
+}
+
+impl Unit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Unit::Milliseconds => "Milliseconds",
+            Unit::Bytes => "Bytes",
+            Unit::Seconds => "Seconds",
+            Unit::Count => "Count",
+        }
+    }
+}
+
+/// Records whether the transcription was served from DynamoDB.
+/// The average of `CacheHit` is the cache-hit rate.
+pub fn cache_lookup(task_type: &TaskType, hit: bool) {
+    emit(
+        task_type,
+        &[("CacheHit", u8::from(hit).into(), Unit::Count)],
+    );
+}
+
+/// Records the time it took Groq to transcribe the file along with the file size and audio duration.
+pub fn transcription(task_type: &TaskType, elapsed_ms: u128, file_size: usize, duration: u32) {
+    emit(
+        task_type,
+        &[
+            ("TranscriptionTime", elapsed_ms as f64, Unit::Milliseconds),
+            ("FileSize", file_size as f64, Unit::Bytes),
+            ("AudioDuration", duration.into(), Unit::Seconds),
+        ],
+    );
+}
+
+fn emit(task_type: &TaskType, metrics: &[(&str, f64, Unit)]) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+
+    let definitions: Vec<Value> = metrics
+        .iter()
+        .map(|(name, _, unit)| json!({ "Name": name, "Unit": unit.as_str() }))
+        .collect();
+
+    let mut line = Map::new();
+    line.insert(
+        "_aws".to_string(),
+        json!({
+            "Timestamp": timestamp,
+            "CloudWatchMetrics": [{
+                "Namespace": NAMESPACE,
+                "Dimensions": [["TaskType"]],
+                "Metrics": definitions,
+            }],
+        }),
+    );
+    line.insert("TaskType".to_string(), json!(task_type.to_string()));
+    for (name, value, _) in metrics {
+        line.insert(name.to_string(), json!(value));
+    }
+
+    // This has to be a raw line on stdout, the tracing formatter would prefix it and break the JSON
+    println!("{}", Value::Object(line));
+}
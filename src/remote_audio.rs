@@ -0,0 +1,113 @@
+use mime::Mime;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::utils::mime_from_extension;
+use crate::MAX_FILE_SIZE;
+
+const DOWNLOAD_TIMEOUT_SECONDS: u64 = 20;
+
+/// Returns the first http(s) link in the text that points to a file with a known audio extension
+pub fn find_audio_url(text: &str) -> Option<(Url, Mime)> {
+    text.split_whitespace().find_map(|word| {
+        let url = Url::parse(word).ok()?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return None;
+        }
+        let mime = mime_from_extension(url.path().rsplit_once('.')?.1)?;
+        Some((url, mime))
+    })
+}
+
+// Only public addresses, the Lambda shouldn't be usable to reach internal or metadata endpoints
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b))) // carrier-grade NAT
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (first & 0xfe00) == 0xfc00 // unique local
+                || (first & 0xffc0) == 0xfe80) // link local
+        }
+    }
+}
+
+/// Downloads the linked file, refusing private addresses and anything above MAX_FILE_SIZE
+pub async fn download(url: &Url) -> Result<Vec<u8>, String> {
+    let host = url.host_str().ok_or("The link has no host")?;
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| format!("Couldn't resolve {host}"))?
+        .collect();
+    let Some(addr) = addrs.first().copied() else {
+        return Err(format!("Couldn't resolve {host}"));
+    };
+    if addrs.iter().any(|addr| !is_public_ip(addr.ip())) {
+        warn!("Refusing to download from non-public address: {}", url);
+        return Err("Links to private addresses are not allowed".to_string());
+    }
+
+    // Pin the checked address so a second DNS lookup can't point somewhere else,
+    // and don't follow redirects since they would skip the check
+    let client = reqwest::Client::builder()
+        .resolve(host, addr)
+        .redirect(Policy::none())
+        .timeout(Duration::from_secs(DOWNLOAD_TIMEOUT_SECONDS))
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut res = client
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(|e| format!("Failed to download the file: {e}"))?;
+    if !res.status().is_success() {
+        return Err(format!("Failed to download the file: {}", res.status()));
+    }
+
+    let max_size = MAX_FILE_SIZE as usize * 1024 * 1024;
+    let too_large = || format!("File can't be larger than {MAX_FILE_SIZE}MB");
+    if res
+        .content_length()
+        .is_some_and(|length| length > max_size as u64)
+    {
+        return Err(too_large());
+    }
+
+    // Content-Length can be missing or wrong, so count the bytes as they come in
+    let mut audio_bytes = Vec::new();
+    while let Some(chunk) = res
+        .chunk()
+        .await
+        .map_err(|e| format!("Failed to download the file: {e}"))?
+    {
+        if audio_bytes.len() + chunk.len() > max_size {
+            return Err(too_large());
+        }
+        audio_bytes.extend_from_slice(&chunk);
+    }
+
+    info!("Downloaded {} bytes from {}", audio_bytes.len(), url);
+    Ok(audio_bytes)
+}
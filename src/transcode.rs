@@ -0,0 +1,131 @@
+use std::env;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::process::Command;
+use tracing::{debug, error, info};
+
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// On Lambda ffmpeg comes from a layer, which puts it in /opt/bin (already in PATH)
+fn ffmpeg_path() -> String {
+    env::var("FFMPEG_PATH").unwrap_or_else(|_| "ffmpeg".to_string())
+}
+
+/// Runs ffmpeg on the input and returns what it writes to stdout.
+/// Returns None if ffmpeg isn't available or fails, callers should fall back to the original file.
+async fn run_ffmpeg(input: &[u8], args: &[&str]) -> Option<Vec<u8>> {
+    // mp4 files often have their index at the end, so ffmpeg needs a seekable file instead of stdin
+    let path = env::temp_dir().join(format!(
+        "duck_transcriber_{}_{}",
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    if let Err(e) = tokio::fs::write(&path, input).await {
+        error!("Failed to write temp file for ffmpeg: {:?}", e);
+        return None;
+    }
+
+    let output = Command::new(ffmpeg_path())
+        .args(["-hide_banner", "-loglevel", "error", "-i"])
+        .arg(&path)
+        .args(args)
+        .arg("pipe:1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await;
+
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        error!("Failed to remove temp file: {:?}", e);
+    }
+
+    match output {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            debug!("ffmpeg not found, skipping");
+            None
+        }
+        Err(e) => {
+            error!("Failed to run ffmpeg: {:?}", e);
+            None
+        }
+        Ok(output) if !output.status.success() => {
+            error!(
+                "ffmpeg failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            None
+        }
+        Ok(output) => Some(output.stdout),
+    }
+}
+
+/// Drops the video track and encodes the audio as mono Opus in an ogg container (audio/ogg)
+pub async fn extract_audio(input: &[u8]) -> Option<Vec<u8>> {
+    let audio = run_ffmpeg(
+        input,
+        &[
+            "-vn", "-ac", "1", "-c:a", "libopus", "-b:a", "48k", "-f", "ogg",
+        ],
+    )
+    .await?;
+
+    info!(
+        "Extracted audio track: {} bytes -> {} bytes",
+        input.len(),
+        audio.len()
+    );
+    Some(audio)
+}
+
+/// Cuts out the audio between start and end (in seconds), encoded like extract_audio
+pub async fn trim(input: &[u8], start: u32, end: u32) -> Option<Vec<u8>> {
+    let (start, end) = (start.to_string(), end.to_string());
+    let audio = run_ffmpeg(
+        input,
+        &[
+            "-ss", &start, "-to", &end, "-vn", "-ac", "1", "-c:a", "libopus", "-b:a", "48k", "-f",
+            "ogg",
+        ],
+    )
+    .await?;
+
+    info!(
+        "Trimmed audio to {}s-{}s: {} bytes -> {} bytes",
+        start,
+        end,
+        input.len(),
+        audio.len()
+    );
+    Some(audio)
+}
+
+/// `NORMALIZE_AUDIO=true` turns on loudness normalization before transcription
+pub fn normalization_enabled() -> bool {
+    env::var("NORMALIZE_AUDIO").is_ok_and(|value| value.trim().eq_ignore_ascii_case("true"))
+}
+
+/// Runs the audio through ffmpeg's loudnorm filter so faint recordings don't get dropped as silence.
+/// Also drops any video track, the output is mono Opus in an ogg container (audio/ogg).
+pub async fn normalize_loudness(input: &[u8]) -> Option<Vec<u8>> {
+    let audio = run_ffmpeg(
+        input,
+        &[
+            "-vn",
+            "-af",
+            "loudnorm=I=-16:TP=-1.5:LRA=11",
+            "-ac",
+            "1",
+            "-c:a",
+            "libopus",
+            "-b:a",
+            "48k",
+            "-f",
+            "ogg",
+        ],
+    )
+    .await?;
+
+    info!("Normalized audio loudness ({} bytes)", audio.len());
+    Some(audio)
+}
@@ -1,26 +1,110 @@
+use crate::utils::format_duration;
+use crate::utils::mask_key;
 use crate::BASE_URL;
+use crate::OPENAI_BASE_URL;
+use bytes::Bytes;
 use mime::Mime;
 use reqwest::header::HeaderMap;
 use reqwest::header::AUTHORIZATION;
+use reqwest::header::RETRY_AFTER;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::time::Duration;
+use std::time::Instant;
 use tracing::error;
+use tracing::info;
 use tracing::warn;
 
-#[derive(strum::Display)]
+#[derive(strum::Display, strum::EnumIter, Clone, Copy)]
 pub enum TaskType {
     #[strum(to_string = "transcribe")]
     Transcribe,
     #[strum(to_string = "translate")]
     Translate,
+    #[strum(to_string = "diarize")]
+    Diarize,
+    #[strum(to_string = "raw")]
+    Raw, // Transcription without the silence filter
+    #[strum(to_string = "clean")]
+    Clean, // Transcription without filler words, cleaned up by a chat model (see /clean)
 }
 
+// A pause longer than this between two segments is treated as a speaker change when diarizing
+pub const DIARIZE_GAP_SECONDS: f64 = 1.5;
+
+#[derive(Debug)]
+pub enum TranscriptionError {
+    RequestError(String),
+    RateLimitReached(Option<u64>), // seconds until the limit resets, if the API said
+    Timeout,
+    InvalidAudio,
+    EnglishOnlyModel, // translation was requested with a model that only knows English
+    ApiError(String),
+    ParseError(String),
+}
+
+impl fmt::Display for TranscriptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranscriptionError::RequestError(e) => write!(f, "Failed to send request: {e}"),
+            TranscriptionError::RateLimitReached(Some(seconds)) => write!(
+                f,
+                "Rate limit reached, try again in {}.",
+                format_duration(u32::try_from(*seconds).unwrap_or(u32::MAX))
+            ),
+            TranscriptionError::RateLimitReached(None) => write!(f, "Rate limit reached."),
+            TranscriptionError::Timeout => write!(
+                f,
+                "Transcription timed out. Try a shorter clip or retry in a moment."
+            ),
+            TranscriptionError::InvalidAudio => {
+                write!(f, "The audio was too short or couldn't be read.")
+            }
+            TranscriptionError::EnglishOnlyModel => write!(
+                f,
+                "This chat uses the English-only model, which can't translate. Switch back with /model large."
+            ),
+            TranscriptionError::ApiError(code) => {
+                write!(f, "Whisper API returned an error: {code}")
+            }
+            TranscriptionError::ParseError(e) => write!(f, "Failed to parse Whisper response: {e}"),
+        }
+    }
+}
+
+// Segments above this are considered a repetition loop (same default as the Whisper reference implementation)
+pub const MAX_COMPRESSION_RATIO: f64 = 2.4;
+
+// Mean avg_logprob below this is flagged as low confidence in the reply
+pub const LOW_CONFIDENCE_THRESHOLD: f64 = -0.6;
+
+pub struct Transcription {
+    pub text: String,
+    // Mean avg_logprob of the kept segments, weighted by segment duration
+    pub confidence: f64,
+}
+
+impl Transcription {
+    pub fn is_low_confidence(&self) -> bool {
+        self.confidence < LOW_CONFIDENCE_THRESHOLD
+    }
+}
+
+// Only text is required, some servers (and Groq on odd files) leave out the rest
 #[derive(Debug, Deserialize, Serialize)]
-struct OpenAIWhisperResponse {
+pub struct OpenAIWhisperResponse {
+    #[serde(default)]
     task: String,
+    #[serde(default)]
     language: String,
+    #[serde(default)]
     duration: f64,
     text: String,
+    #[serde(default)]
     segments: Vec<OpenAIWhisperSegment>,
 }
 
@@ -38,95 +122,1079 @@ struct OpenAIWhisperSegment {
     no_speech_prob: f64,
 }
 
-pub async fn transcribe(
+impl OpenAIWhisperResponse {
+    /// Subtitles with one cue per kept segment
+    pub fn to_srt(&self) -> String {
+        self.segments
+            .iter()
+            .filter(|segment| keep_segment(&TaskType::Transcribe, segment))
+            .enumerate()
+            .map(|(i, segment)| {
+                format!(
+                    "{}\n{} --> {}\n{}\n",
+                    i + 1,
+                    srt_timestamp(segment.start),
+                    srt_timestamp(segment.end),
+                    segment.text.trim()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+// Inline markers are only added at a segment boundary at least this far from the last one
+const STAMP_INTERVAL_SECONDS: f64 = 15.0;
+
+impl OpenAIWhisperResponse {
+    /// The transcript with a `[M:SS]` marker starting a new line roughly every 15 seconds
+    pub fn to_stamped_text(&self) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        let mut last_stamp: Option<f64> = None;
+        for segment in self
+            .segments
+            .iter()
+            .filter(|segment| keep_segment(&TaskType::Transcribe, segment))
+        {
+            let text = segment.text.trim();
+            match lines.last_mut() {
+                Some(line)
+                    if last_stamp
+                        .is_some_and(|last| segment.start - last < STAMP_INTERVAL_SECONDS) =>
+                {
+                    line.push(' ');
+                    line.push_str(text);
+                }
+                _ => {
+                    last_stamp = Some(segment.start);
+                    lines.push(format!(
+                        "[{}] {text}",
+                        format_duration(segment.start.max(0.0) as u32)
+                    ));
+                }
+            }
+        }
+        lines.join("\n")
+    }
+}
+
+// 00:01:02,345
+fn srt_timestamp(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+pub trait TranscriptionProvider {
+    /// Returns the full verbose_json response, including every segment
+    async fn transcribe_verbose(
+        &self,
+        task_type: &TaskType,
+        buffer: Bytes,
+        mime: Mime,
+        prompt: Option<&str>,
+    ) -> Result<OpenAIWhisperResponse, TranscriptionError>;
+
+    async fn transcribe(
+        &self,
+        task_type: &TaskType,
+        buffer: Bytes,
+        mime: Mime,
+        prompt: Option<&str>,
+    ) -> Result<Option<Transcription>, TranscriptionError> {
+        let res = self
+            .transcribe_verbose(task_type, buffer, mime, prompt)
+            .await?;
+        Ok(filter_segments(task_type, res))
+    }
+}
+
+/// The Groq Whisper models a chat can pick with /model
+#[derive(strum::Display, strum::EnumString, strum::EnumIter, Clone, Copy, Default)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum WhisperModel {
+    #[default]
+    Large,
+    Distil, // faster, but only for English audio
+}
+
+impl WhisperModel {
+    pub fn id(&self) -> &'static str {
+        match self {
+            WhisperModel::Large => "whisper-large-v3",
+            WhisperModel::Distil => "distil-whisper-large-v3-en",
+        }
+    }
+
+    pub fn english_only(&self) -> bool {
+        matches!(self, WhisperModel::Distil)
+    }
+}
+
+pub struct GroqProvider {
+    base_url: String,
+    api_key: String,
+    model: WhisperModel,
+    // Used when Groq is rate limited
+    fallback: Option<OpenAiProvider>,
+}
+
+pub struct OpenAiProvider {
+    base_url: String,
+    api_key: String,
+}
+
+/// Picks the provider from `TRANSCRIPTION_PROVIDER` ("groq" or "openai", defaults to groq)
+pub enum Provider {
+    Groq(GroqProvider),
+    OpenAi(OpenAiProvider),
+}
+
+/// `GROQ_BASE_URL` lets self-hosters point at any OpenAI-compatible Whisper server
+pub fn groq_base_url() -> String {
+    env::var("GROQ_BASE_URL")
+        .ok()
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| BASE_URL.to_string())
+}
+
+static MALFORMED_KEY: Once = Once::new();
+static MISSING_OPENAI_KEY: Once = Once::new();
+
+// Groq keys are "gsk_" followed by a long alphanumeric string
+fn looks_like_groq_key(api_key: &str) -> bool {
+    api_key
+        .trim()
+        .strip_prefix("gsk_")
+        .is_some_and(|rest| rest.len() >= 32 && rest.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+impl GroqProvider {
+    pub fn from_env() -> Self {
+        let api_key = env::var("GROQ_API_KEY").expect("GROQ_API_KEY not found");
+        let base_url = groq_base_url();
+        // Self-hosted servers can use any key format, only check keys for Groq itself
+        if base_url == BASE_URL && !looks_like_groq_key(&api_key) {
+            MALFORMED_KEY.call_once(|| {
+                warn!(
+                    "GROQ_API_KEY ({}) doesn't look like a Groq key, requests will probably fail",
+                    mask_key(api_key.trim())
+                )
+            });
+        }
+        // OpenAI is only used as a fallback when Groq is rate limited
+        let fallback = OpenAiProvider::from_env();
+
+        Self {
+            base_url,
+            api_key,
+            model: WhisperModel::default(),
+            fallback,
+        }
+    }
+
+    fn endpoint(&self) -> Endpoint<'_> {
+        Endpoint {
+            provider: "Groq",
+            base_url: &self.base_url,
+            model: self.model.id(),
+            api_key: &self.api_key,
+        }
+    }
+}
+
+impl OpenAiProvider {
+    /// None when `OPENAI_API_KEY` is not set
+    pub fn from_env() -> Option<Self> {
+        let api_key = env::var("OPENAI_API_KEY")
+            .ok()
+            .filter(|key| !key.trim().is_empty())?;
+        Some(Self {
+            base_url: OPENAI_BASE_URL.to_string(),
+            api_key,
+        })
+    }
+
+    fn endpoint(&self) -> Endpoint<'_> {
+        Endpoint {
+            provider: "OpenAI",
+            base_url: &self.base_url,
+            model: "whisper-1",
+            api_key: &self.api_key,
+        }
+    }
+}
+
+impl Provider {
+    pub fn from_env() -> Self {
+        match env::var("TRANSCRIPTION_PROVIDER")
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+            .as_str()
+        {
+            "openai" => match OpenAiProvider::from_env() {
+                Some(provider) => Provider::OpenAi(provider),
+                None => {
+                    // Groq still works, that beats failing every update over a config mistake
+                    MISSING_OPENAI_KEY.call_once(|| {
+                        error!("TRANSCRIPTION_PROVIDER is openai but OPENAI_API_KEY is not set, using Groq")
+                    });
+                    Provider::Groq(GroqProvider::from_env())
+                }
+            },
+            _ => Provider::Groq(GroqProvider::from_env()),
+        }
+    }
+
+    /// Only Groq has a choice of models, OpenAI always uses whisper-1
+    pub fn with_model(self, model: WhisperModel) -> Self {
+        match self {
+            Provider::Groq(provider) => Provider::Groq(GroqProvider { model, ..provider }),
+            provider => provider,
+        }
+    }
+
+    /// e.g. "Groq (whisper-large-v3)", with the fallback if there is one
+    pub fn description(&self) -> String {
+        let (endpoint, fallback) = match self {
+            Provider::Groq(provider) => (provider.endpoint(), provider.fallback.as_ref()),
+            Provider::OpenAi(provider) => (provider.endpoint(), None),
+        };
+        let description = format!("{} ({})", endpoint.provider, endpoint.model);
+        match fallback {
+            Some(fallback) => format!(
+                "{description}, falls back to OpenAI ({})",
+                fallback.endpoint().model
+            ),
+            None => description,
+        }
+    }
+}
+
+impl TranscriptionProvider for GroqProvider {
+    async fn transcribe_verbose(
+        &self,
+        task_type: &TaskType,
+        buffer: Bytes,
+        mime: Mime,
+        prompt: Option<&str>,
+    ) -> Result<OpenAIWhisperResponse, TranscriptionError> {
+        if self.model.english_only() && matches!(task_type, TaskType::Translate) {
+            return Err(TranscriptionError::EnglishOnlyModel);
+        }
+
+        let Some(fallback) = &self.fallback else {
+            return whisper_request(
+                self.endpoint(),
+                task_type,
+                buffer,
+                mime,
+                prompt,
+                request_timeout(),
+            )
+            .await;
+        };
+
+        // Both requests share one budget, otherwise a slow Groq plus the fallback outlasts the Lambda
+        let deadline = Instant::now() + request_timeout();
+        let res = whisper_request(
+            self.endpoint(),
+            task_type,
+            buffer.clone(), // cheap, Bytes is reference counted
+            mime.clone(),
+            prompt,
+            request_timeout(),
+        )
+        .await;
+
+        match res {
+            Err(TranscriptionError::RateLimitReached(retry_after)) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining < MIN_FALLBACK_SECONDS {
+                    warn!("Groq rate limit reached, no time left to fall back to OpenAI");
+                    return Err(TranscriptionError::RateLimitReached(retry_after));
+                }
+                info!("Groq rate limit reached, falling back to OpenAI");
+                whisper_request(
+                    fallback.endpoint(),
+                    task_type,
+                    buffer,
+                    mime,
+                    prompt,
+                    remaining,
+                )
+                .await
+            }
+            res => res,
+        }
+    }
+}
+
+impl TranscriptionProvider for OpenAiProvider {
+    async fn transcribe_verbose(
+        &self,
+        task_type: &TaskType,
+        buffer: Bytes,
+        mime: Mime,
+        prompt: Option<&str>,
+    ) -> Result<OpenAIWhisperResponse, TranscriptionError> {
+        whisper_request(
+            self.endpoint(),
+            task_type,
+            buffer,
+            mime,
+            prompt,
+            request_timeout(),
+        )
+        .await
+    }
+}
+
+impl TranscriptionProvider for Provider {
+    async fn transcribe_verbose(
+        &self,
+        task_type: &TaskType,
+        buffer: Bytes,
+        mime: Mime,
+        prompt: Option<&str>,
+    ) -> Result<OpenAIWhisperResponse, TranscriptionError> {
+        match self {
+            Provider::Groq(provider) => {
+                provider
+                    .transcribe_verbose(task_type, buffer, mime, prompt)
+                    .await
+            }
+            Provider::OpenAi(provider) => {
+                provider
+                    .transcribe_verbose(task_type, buffer, mime, prompt)
+                    .await
+            }
+        }
+    }
+}
+
+// The latest x-ratelimit-* headers per API key, so /usage can show them without extra requests.
+// Only covers this container, other warm containers keep their own.
+static RATE_LIMITS: Mutex<BTreeMap<String, RateLimits>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone)]
+pub struct RateLimits {
+    pub headers: Vec<(String, String)>, // without the x-ratelimit- prefix, e.g. ("remaining-requests", "13999")
+    pub updated: Instant,
+}
+
+impl RateLimits {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// How long to hold off before the key runs out of requests, None if there are enough left
+    /// or the window has reset since the response
+    fn wait_time(&self) -> Option<Duration> {
+        let remaining: u64 = self.header("remaining-requests")?.parse().ok()?;
+        if remaining >= MIN_REMAINING_REQUESTS {
+            return None;
+        }
+        let reset = parse_reset(self.header("reset-requests")?)?;
+        reset
+            .checked_sub(self.updated.elapsed())
+            .filter(|wait| !wait.is_zero())
+    }
+}
+
+// Below this many requests left, new transcriptions wait instead of running into a 429
+const MIN_REMAINING_REQUESTS: u64 = 2;
+
+// "2m59.56s", "7.66s", "1h2m3s" or "120ms"
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut number = String::new();
+    let mut chars = value.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let amount: f64 = number.parse().ok()?;
+        number.clear();
+        total += match c {
+            'h' => amount * 3600.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                amount / 1000.0
+            }
+            'm' => amount * 60.0,
+            's' => amount,
+            _ => return None,
+        };
+    }
+    number.is_empty().then(|| Duration::from_secs_f64(total))
+}
+
+// Checked before sending, a key that's about to hit its limit is treated as if it already did
+fn near_rate_limit(provider: &str, api_key: &str) -> Option<Duration> {
+    RATE_LIMITS
+        .lock()
+        .unwrap()
+        .get(&rate_limit_key(provider, api_key))
+        .and_then(RateLimits::wait_time)
+}
+
+fn rate_limit_key(provider: &str, api_key: &str) -> String {
+    format!("{provider} ({})", mask_key(api_key.trim()))
+}
+
+/// Rate limits from the last response of each key, as "<provider> (<masked key>)"
+pub fn rate_limits() -> Vec<(String, RateLimits)> {
+    RATE_LIMITS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(key, limits)| (key.clone(), limits.clone()))
+        .collect()
+}
+
+fn record_rate_limits(provider: &str, api_key: &str, headers: &HeaderMap) {
+    let headers: Vec<(String, String)> = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let name = name.as_str().strip_prefix("x-ratelimit-")?;
+            Some((name.to_string(), value.to_str().ok()?.to_string()))
+        })
+        .collect();
+    if headers.is_empty() {
+        return;
+    }
+
+    RATE_LIMITS.lock().unwrap().insert(
+        rate_limit_key(provider, api_key),
+        RateLimits {
+            headers,
+            updated: Instant::now(),
+        },
+    );
+}
+
+// Enough to see what came back without flooding the logs with a whole transcription
+const MAX_LOGGED_BODY_CHARS: usize = 1000;
+
+// Default stays below the Lambda timeout, so the user gets a message instead of a killed invocation
+const DEFAULT_TIMEOUT_SECONDS: u64 = 50;
+
+// The fallback isn't worth starting with less than this left of the budget
+const MIN_FALLBACK_SECONDS: Duration = Duration::from_secs(5);
+
+/// The time a transcription may take in total, including the OpenAI fallback
+fn request_timeout() -> Duration {
+    let Ok(seconds) = env::var("TRANSCRIPTION_TIMEOUT_SECONDS") else {
+        return Duration::from_secs(DEFAULT_TIMEOUT_SECONDS);
+    };
+    match seconds.trim().parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+        _ => {
+            warn!(
+                "TRANSCRIPTION_TIMEOUT_SECONDS must be a positive number of seconds, got {:?}",
+                seconds
+            );
+            Duration::from_secs(DEFAULT_TIMEOUT_SECONDS)
+        }
+    }
+}
+
+// 0 is the most deterministic, so the same clip gives the same text (and the cache stays meaningful)
+const DEFAULT_TEMPERATURE: f32 = 0.0;
+
+fn temperature() -> f32 {
+    let Ok(temperature) = env::var("TRANSCRIPTION_TEMPERATURE") else {
+        return DEFAULT_TEMPERATURE;
+    };
+    match temperature.trim().parse::<f32>() {
+        Ok(temperature) if (0.0..=1.0).contains(&temperature) => temperature,
+        _ => {
+            warn!(
+                "TRANSCRIPTION_TEMPERATURE must be between 0 and 1, got {:?}",
+                temperature
+            );
+            DEFAULT_TEMPERATURE
+        }
+    }
+}
+
+fn request_error(provider: &str, err: reqwest::Error) -> TranscriptionError {
+    if err.is_timeout() {
+        warn!("Request to {} timed out", provider);
+        return TranscriptionError::Timeout;
+    }
+    error!("Failed to send request to {}: {}", provider, err);
+    TranscriptionError::RequestError(err.to_string())
+}
+
+// Both APIs speak the same Whisper protocol, only the url and model differ
+struct Endpoint<'a> {
+    provider: &'a str,
+    base_url: &'a str,
+    model: &'a str,
+    api_key: &'a str,
+}
+
+async fn whisper_request(
+    endpoint: Endpoint<'_>,
     task_type: &TaskType,
-    buffer: Vec<u8>,
+    buffer: Bytes,
     mime: Mime,
-) -> Result<Option<String>, String> {
-    // Set Groq API headers
+    prompt: Option<&str>,
+    timeout: Duration,
+) -> Result<OpenAIWhisperResponse, TranscriptionError> {
+    let Endpoint {
+        provider,
+        base_url,
+        model,
+        api_key,
+    } = endpoint;
+
+    // Same as a 429, so Groq still falls back to OpenAI and users are told how long to wait
+    if let Some(wait) = near_rate_limit(provider, api_key) {
+        warn!(
+            "{} is almost out of requests, waiting {:?} before the next one",
+            provider, wait
+        );
+        return Err(TranscriptionError::RateLimitReached(Some(
+            wait.as_secs_f64().ceil() as u64,
+        )));
+    }
+
+    // Set API headers
     let mut headers: HeaderMap = HeaderMap::new();
     headers.insert(
         AUTHORIZATION,
-        format!(
-            "Bearer {}",
-            env::var("GROQ_API_KEY").expect("GROQ_API_KEY not found")
-        )
-        .parse()
-        .unwrap(),
+        format!("Bearer {}", api_key.trim()).parse().unwrap(),
     );
 
     // Create multipart request
-    let part = reqwest::multipart::Part::bytes(buffer)
+    // Part::bytes would need an owned Vec, which is a full copy whenever the fallback still holds the buffer.
+    // A Body made from Bytes just shares it, e.g. a 20MB file with a fallback is 20MB instead of 40MB.
+    let length = buffer.len() as u64;
+    let part = reqwest::multipart::Part::stream_with_length(reqwest::Body::from(buffer), length)
         .file_name(format!("audio.{}", mime.subtype()))
         .mime_str(mime.as_ref())
         .unwrap();
-    let form = reqwest::multipart::Form::new()
-        .text("model", "whisper-large-v3")
+    let mut form = reqwest::multipart::Form::new()
+        .text("model", model.to_string())
         .text("response_format", "verbose_json")
+        .text("temperature", temperature().to_string())
         .part("file", part);
+    // Whisper uses the prompt as preceding context, it helps with names and jargon
+    if let Some(prompt) = prompt {
+        form = form.text("prompt", prompt.to_string());
+    }
 
-    // Send file to Groq Whisper for transcription
+    // Send file to Whisper for transcription
     let client = reqwest::Client::new();
     let url_ending = match task_type {
-        TaskType::Transcribe => "/audio/transcriptions",
+        TaskType::Transcribe | TaskType::Diarize | TaskType::Raw | TaskType::Clean => {
+            "/audio/transcriptions"
+        }
         TaskType::Translate => "/audio/translations",
     };
 
     let res = client
-        .post(format!("{BASE_URL}{url_ending}"))
+        .post(format!("{base_url}{url_ending}"))
         .multipart(form)
         .headers(headers)
+        .timeout(timeout)
         .send()
         .await
-        .map_err(|err| {
-            error!("Failed to send request to OpenAI: {}", err);
-            format!("Failed to send request to OpenAI: {err}")
-        })?;
+        .map_err(|err| request_error(provider, err))?;
 
     // IT'S EXTREMELY IMPORTANT TO HANDLE EVERY ERROR FROM HERE. WE CANNOT RETURN STATUS OTHER THEN 200, TELEGRAM IS GOING TO KEEP SENDING THE WEBHOOK AGAIN CREATING AN INFINITE LOOP.
-    // Check if Groq returned an error
+    record_rate_limits(provider, api_key, res.headers());
+
+    // Check if the API returned an error
     let status = res.status();
     if !status.is_success() {
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u64>().ok());
         let json = res
             .json::<serde_json::Value>()
             .await
-            .map_err(|err| format!("Failed to parse OpenAI error response: {err}"))
-            .unwrap();
+            .map_err(|err| TranscriptionError::ParseError(err.to_string()))?;
 
         if json["error"]["code"] == "rate_limit_exceeded" {
             warn!("Rate limit reached. Here is the response: {:?}", json);
+            let retry_after = retry_after.or_else(|| json_retry_after(&json));
+            return Err(TranscriptionError::RateLimitReached(retry_after));
+        }
 
-            // DONT CHANGE THIS STRING!
-            return Err("Rate limit reached.".to_string());
+        if is_invalid_audio(&json) {
+            warn!("{} couldn't read the audio: {:?}", provider, json);
+            return Err(TranscriptionError::InvalidAudio);
         }
 
-        error!("Groq returned an error: {:?}", json);
-        return Err(format!("Groq returned an error: {}", json["error"]["code"]));
+        error!("{} returned an error: {:?}", provider, json);
+        return Err(TranscriptionError::ApiError(
+            json["error"]["code"].to_string(),
+        ));
     }
 
     // Extract all of the segments
-    let res = res
-        .json::<OpenAIWhisperResponse>()
-        .await
-        .map_err(|err| format!("Failed to parse OpenAI response: {err}"))
-        .unwrap();
+    let body = res.text().await.map_err(|err| {
+        if err.is_timeout() {
+            request_error(provider, err)
+        } else {
+            TranscriptionError::ParseError(err.to_string())
+        }
+    })?;
+    serde_json::from_str::<OpenAIWhisperResponse>(&body).map_err(|err| {
+        // Otherwise a schema change only shows up as a vague parse error
+        let truncated: String = body.chars().take(MAX_LOGGED_BODY_CHARS).collect();
+        error!(
+            "Unexpected response from {}: {} | body: {}",
+            provider, err, truncated
+        );
+        TranscriptionError::ParseError(err.to_string())
+    })
+}
+
+// Some responses only have it in the body, as whole or fractional seconds
+fn json_retry_after(json: &serde_json::Value) -> Option<u64> {
+    [&json["retry_after"], &json["error"]["retry_after"]]
+        .into_iter()
+        .find_map(serde_json::Value::as_f64)
+        .map(|seconds| seconds.ceil() as u64)
+}
+
+// Empty, too short or corrupt files. Groq doesn't always set a code, so the message is checked too
+fn is_invalid_audio(json: &serde_json::Value) -> bool {
+    const CODES: &[&str] = &["audio_too_short", "invalid_file_format"];
+    const MESSAGES: &[&str] = &["too short", "could not process file", "valid media file"];
 
+    let code = json["error"]["code"].as_str().unwrap_or_default();
+    let message = json["error"]["message"]
+        .as_str()
+        .unwrap_or_default()
+        .to_lowercase();
+    CODES.contains(&code) || MESSAGES.iter().any(|m| message.contains(m))
+}
+
+// Video outros Whisper emits for silent or near-silent audio. Matched against the whole transcription,
+// ignoring case and punctuation. Short words like "bye" are left out, people do send those
+const KNOWN_HALLUCINATIONS: &[&str] = &[
+    "thanks for watching",
+    "thank you for watching",
+    "thank you so much for watching",
+];
+
+fn is_known_hallucination(text: &str) -> bool {
+    let text = text
+        .trim_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation() || c == '—')
+        .to_lowercase();
+    KNOWN_HALLUCINATIONS.contains(&text.as_str())
+}
+
+fn keep_segment(task_type: &TaskType, segment: &OpenAIWhisperSegment) -> bool {
+    if matches!(task_type, TaskType::Raw) {
+        return true;
+    }
+
+    // If the no_speech_prob value is higher than 1.0 and the avg_logprob is below -1, consider this segment silent.
+    // These values are fine-tuned from a lot of testing. They work way better than the default values.
+    if segment.no_speech_prob > 0.6 && segment.avg_logprob < -0.4 {
+        return false;
+    }
+
+    // Text that compresses this well is Whisper repeating itself
+    if segment.compression_ratio > MAX_COMPRESSION_RATIO {
+        info!(
+            "Dropping repetitive segment (compression ratio {:.2}): {:?}",
+            segment.compression_ratio, segment.text
+        );
+        return false;
+    }
+
+    true
+}
+
+// Drops silent segments and joins the rest, both providers go through this so the output is identical
+pub fn filter_segments(task_type: &TaskType, res: OpenAIWhisperResponse) -> Option<Transcription> {
     let mut output_text = String::new();
+    let mut weighted_logprob = 0.0;
+    let mut total_duration = 0.0;
+    let mut previous_end: Option<f64> = None;
 
     // Extract all of the segments.
+    let segments_missing = res.segments.is_empty();
     for segment in res.segments {
-        // If the no_speech_prob value is higher than 1.0 and the avg_logprob is below -1, consider this segment silent.
-        // These values are fine-tuned from a lot of testing. They work way better than the default values.
-        if segment.no_speech_prob > 0.6 && segment.avg_logprob < -0.4 {
+        if !keep_segment(task_type, &segment) {
             continue;
         }
+
+        // Whisper can't tell speakers apart, so long pauses are the best guess we have
+        if matches!(task_type, TaskType::Diarize) {
+            match previous_end {
+                None => output_text += "—",
+                Some(end) if segment.start - end > DIARIZE_GAP_SECONDS => output_text += "\n\n—",
+                Some(_) => {}
+            }
+            previous_end = Some(segment.end);
+        }
         output_text += &segment.text;
+
+        let segment_duration = (segment.end - segment.start).max(0.0);
+        weighted_logprob += segment.avg_logprob * segment_duration;
+        total_duration += segment_duration;
+    }
+
+    // A response without segments still has the text, there's just nothing to filter
+    if segments_missing && !res.text.trim().is_empty() {
+        warn!("Response has no segments, using its text unfiltered");
+        output_text = res.text;
     }
 
     // If the output text is empty, return <no text>
     if output_text.is_empty() {
-        return Ok(None);
+        return None;
+    }
+
+    // Raw is meant to show everything Whisper returned, so it keeps these
+    if !matches!(task_type, TaskType::Raw) && is_known_hallucination(&output_text) {
+        info!("Dropping known hallucination: {:?}", output_text.trim());
+        return None;
+    }
+
+    let confidence = if total_duration > 0.0 {
+        weighted_logprob / total_duration
+    } else {
+        0.0
+    };
+
+    Some(Transcription {
+        text: output_text,
+        confidence,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    const TRANSCRIPTION: &str = r#"{
+        "task": "transcribe",
+        "language": "english",
+        "duration": 2.0,
+        "text": " Hello there.",
+        "segments": [{
+            "id": 0, "seek": 0, "start": 0.0, "end": 2.0, "text": " Hello there.",
+            "tokens": [1, 2, 3], "temperature": 0.0, "avg_logprob": -0.2,
+            "compression_ratio": 0.8, "no_speech_prob": 0.01
+        }]
+    }"#;
+
+    const RATE_LIMITED: &str =
+        r#"{"error": {"message": "Rate limit reached", "code": "rate_limit_exceeded"}}"#;
+
+    // Answers one request with the given status line, extra headers and JSON body.
+    // Returns the base url to point a provider at.
+    async fn mock_server(
+        status: &'static str,
+        headers: &'static str,
+        body: &'static str,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+
+            // Read the whole request first, otherwise the client can see a reset connection
+            let mut content_length = 0;
+            let mut chunked = false;
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                let lower = line.to_lowercase();
+                if let Some(length) = lower.strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+                if lower.starts_with("transfer-encoding:") && lower.contains("chunked") {
+                    chunked = true;
+                }
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+            }
+            if chunked {
+                let mut body = Vec::new();
+                while !body.ends_with(b"0\r\n\r\n") {
+                    let mut byte = [0; 1];
+                    stream.read_exact(&mut byte).await.unwrap();
+                    body.push(byte[0]);
+                }
+            } else {
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).await.unwrap();
+            }
+
+            let response = format!(
+                "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n{headers}\r\n{body}",
+                body.len()
+            );
+            stream
+                .get_mut()
+                .write_all(response.as_bytes())
+                .await
+                .unwrap();
+            stream.get_mut().shutdown().await.unwrap();
+        });
+
+        format!("http://{addr}")
+    }
+
+    fn groq(base_url: String, api_key: &str, fallback: Option<OpenAiProvider>) -> GroqProvider {
+        GroqProvider {
+            base_url,
+            api_key: api_key.to_string(),
+            model: WhisperModel::default(),
+            fallback,
+        }
+    }
+
+    fn openai(base_url: String, api_key: &str) -> OpenAiProvider {
+        OpenAiProvider {
+            base_url,
+            api_key: api_key.to_string(),
+        }
+    }
+
+    async fn transcribe(
+        provider: &impl TranscriptionProvider,
+    ) -> Result<Option<Transcription>, TranscriptionError> {
+        provider
+            .transcribe(
+                &TaskType::Transcribe,
+                Bytes::from_static(b"OggS fake audio"),
+                "audio/ogg".parse().unwrap(),
+                None,
+            )
+            .await
+    }
+
+    #[test]
+    fn groq_key_empty() {
+        assert!(!looks_like_groq_key(""));
+        assert!(!looks_like_groq_key("   \n"));
+    }
+
+    #[test]
+    fn groq_key_wrong_prefix() {
+        assert!(!looks_like_groq_key(
+            "sk-proj-abcdefghijklmnopqrstuvwxyz0123456789"
+        ));
+        assert!(!looks_like_groq_key(
+            "GSK_abcdefghijklmnopqrstuvwxyz0123456789"
+        ));
+    }
+
+    #[test]
+    fn groq_key_bad_shape() {
+        // Too short, and not alphanumeric
+        assert!(!looks_like_groq_key("gsk_abc123"));
+        assert!(!looks_like_groq_key(
+            "gsk_abcdefghijklmnop-qrstuvwxyz0123456789"
+        ));
+    }
+
+    #[test]
+    fn groq_key_valid() {
+        let key = "gsk_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRST0123456789";
+        assert!(looks_like_groq_key(key));
+        // Stray whitespace from copying it into .env is fine
+        assert!(looks_like_groq_key(&format!("  {key}\n")));
+    }
+
+    #[test]
+    fn hallucination_exact() {
+        assert!(is_known_hallucination("thanks for watching"));
+        assert!(is_known_hallucination("Thank you for watching"));
+        assert!(is_known_hallucination("THANKS FOR WATCHING"));
+    }
+
+    #[test]
+    fn hallucination_with_whitespace_and_punctuation() {
+        assert!(is_known_hallucination(" Thanks for watching."));
+        assert!(is_known_hallucination(
+            "\n  Thank you so much for watching!  "
+        ));
+        assert!(is_known_hallucination("— Thank you for watching —"));
+    }
+
+    #[test]
+    fn hallucination_non_matches() {
+        assert!(!is_known_hallucination(""));
+        assert!(!is_known_hallucination("Thank you for coming today."));
+        assert!(!is_known_hallucination("you know what I mean"));
+        assert!(!is_known_hallucination("Bye bye"));
+        // Real short notes
+        assert!(!is_known_hallucination("you"));
+        assert!(!is_known_hallucination("Thank you."));
+        assert!(!is_known_hallucination("Bye!"));
+        assert!(!is_known_hallucination("Please subscribe"));
+    }
+
+    fn segment(text: &str, start: f64, compression_ratio: f64) -> OpenAIWhisperSegment {
+        OpenAIWhisperSegment {
+            id: 0,
+            seek: 0,
+            start,
+            end: start + 2.0,
+            text: text.to_string(),
+            tokens: Vec::new(),
+            temperature: 0.0,
+            avg_logprob: -0.2,
+            compression_ratio,
+            no_speech_prob: 0.01,
+        }
+    }
+
+    fn response(segments: Vec<OpenAIWhisperSegment>) -> OpenAIWhisperResponse {
+        OpenAIWhisperResponse {
+            task: "transcribe".to_string(),
+            language: "english".to_string(),
+            duration: segments.last().map_or(0.0, |segment| segment.end),
+            text: segments
+                .iter()
+                .map(|segment| segment.text.as_str())
+                .collect(),
+            segments,
+        }
+    }
+
+    const REPETITIVE: &str = " the the the the the the the the the the the the";
+
+    #[test]
+    fn keep_segment_drops_repetitive() {
+        let repetitive = segment(REPETITIVE, 0.0, MAX_COMPRESSION_RATIO + 1.0);
+        assert!(!keep_segment(&TaskType::Transcribe, &repetitive));
+        assert!(!keep_segment(&TaskType::Translate, &repetitive));
+        assert!(keep_segment(&TaskType::Raw, &repetitive));
+    }
+
+    #[test]
+    fn keep_segment_keeps_normal() {
+        let normal = segment(" Hello there.", 0.0, MAX_COMPRESSION_RATIO - 1.0);
+        assert!(keep_segment(&TaskType::Transcribe, &normal));
+        // Right at the limit still counts as normal speech
+        let borderline = segment(" Hello there.", 0.0, MAX_COMPRESSION_RATIO);
+        assert!(keep_segment(&TaskType::Transcribe, &borderline));
+    }
+
+    #[test]
+    fn filter_segments_drops_repetitive() {
+        let res = response(vec![
+            segment(" Hello there.", 0.0, 1.2),
+            segment(REPETITIVE, 2.0, 6.5),
+            segment(" How are you?", 4.0, 1.1),
+        ]);
+        let transcription = filter_segments(&TaskType::Transcribe, res).unwrap();
+        assert_eq!(transcription.text, " Hello there. How are you?");
+    }
+
+    #[test]
+    fn filter_segments_raw_keeps_repetitive() {
+        let res = response(vec![
+            segment(" Hello there.", 0.0, 1.2),
+            segment(REPETITIVE, 2.0, 6.5),
+        ]);
+        let transcription = filter_segments(&TaskType::Raw, res).unwrap();
+        assert_eq!(transcription.text, format!(" Hello there.{REPETITIVE}"));
+    }
+
+    #[test]
+    fn filter_segments_only_repetitive_is_none() {
+        let res = response(vec![segment(REPETITIVE, 0.0, 6.5)]);
+        assert!(filter_segments(&TaskType::Transcribe, res).is_none());
+    }
+
+    #[test]
+    fn request_timeout_rejects_invalid() {
+        // Only invalid values are set, so tests running alongside still get the default
+        for value in ["0", "soon", "-5"] {
+            env::set_var("TRANSCRIPTION_TIMEOUT_SECONDS", value);
+            assert_eq!(
+                request_timeout(),
+                Duration::from_secs(DEFAULT_TIMEOUT_SECONDS)
+            );
+        }
+        env::remove_var("TRANSCRIPTION_TIMEOUT_SECONDS");
+    }
+
+    #[tokio::test]
+    async fn whisper_ok_returns_transcription() {
+        let base_url = mock_server("200 OK", "", TRANSCRIPTION).await;
+        let provider = groq(base_url, "test-key-ok", None);
+
+        let transcription = transcribe(&provider).await.unwrap().unwrap();
+        assert_eq!(transcription.text, " Hello there.");
+        assert!(!transcription.is_low_confidence());
+    }
+
+    #[tokio::test]
+    async fn whisper_429_is_rate_limit() {
+        let base_url =
+            mock_server("429 Too Many Requests", "retry-after: 7\r\n", RATE_LIMITED).await;
+        let provider = groq(base_url, "test-key-429", None);
+
+        match transcribe(&provider).await {
+            Err(TranscriptionError::RateLimitReached(Some(7))) => {}
+            res => panic!(
+                "expected a rate limit with retry-after, got {:?}",
+                res.err()
+            ),
+        }
     }
 
-    Ok(Some(output_text))
+    #[tokio::test]
+    async fn whisper_429_falls_back_to_openai() {
+        let groq_url = mock_server("429 Too Many Requests", "", RATE_LIMITED).await;
+        let openai_url = mock_server("200 OK", "", TRANSCRIPTION).await;
+        let provider = groq(
+            groq_url,
+            "test-key-fallback",
+            Some(openai(openai_url, "test-key-openai")),
+        );
+
+        let transcription = transcribe(&provider).await.unwrap().unwrap();
+        assert_eq!(transcription.text, " Hello there.");
+    }
+
+    #[tokio::test]
+    async fn whisper_500_is_api_error() {
+        let base_url = mock_server(
+            "500 Internal Server Error",
+            "",
+            r#"{"error": {"message": "Something broke", "code": "internal_error"}}"#,
+        )
+        .await;
+        let provider = groq(base_url, "test-key-500", None);
+
+        match transcribe(&provider).await {
+            Err(TranscriptionError::ApiError(code)) => assert_eq!(code, r#""internal_error""#),
+            res => panic!("expected an API error, got {:?}", res.err()),
+        }
+    }
 }
@@ -0,0 +1,110 @@
+use std::env;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+use strum::IntoEnumIterator;
+use tracing::{error, info};
+
+use crate::OPENAI_BASE_URL;
+
+// OpenAI rejects longer inputs
+pub const MAX_INPUT_LENGTH: usize = 4096;
+
+#[derive(strum::Display, strum::EnumString, strum::EnumIter, Clone, Copy)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Voice {
+    Alloy,
+    Echo,
+    Fable,
+    Onyx,
+    Nova,
+    Shimmer,
+}
+
+impl Voice {
+    pub fn random() -> Self {
+        let voices: Vec<Voice> = Voice::iter().collect();
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as usize)
+            .unwrap_or_default();
+        voices[nanos % voices.len()]
+    }
+}
+
+#[derive(Debug)]
+pub enum TtsError {
+    NotConfigured,
+    TooLong,
+    RequestError(String),
+    ApiError(String),
+}
+
+impl fmt::Display for TtsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TtsError::NotConfigured => write!(f, "Text to speech is not available."),
+            TtsError::TooLong => write!(
+                f,
+                "Text can't be longer than {MAX_INPUT_LENGTH} characters."
+            ),
+            TtsError::RequestError(e) => write!(f, "Failed to send request: {e}"),
+            TtsError::ApiError(code) => write!(f, "OpenAI returned an error: {code}"),
+        }
+    }
+}
+
+fn api_key() -> Option<String> {
+    env::var("OPENAI_API_KEY")
+        .ok()
+        .filter(|key| !key.trim().is_empty())
+}
+
+/// Text to speech uses OpenAI, so it's only available when `OPENAI_API_KEY` is set
+pub fn enabled() -> bool {
+    api_key().is_some()
+}
+
+/// Returns the speech as Opus in an ogg container, which Telegram plays as a voice message
+pub async fn speak(text: &str, voice: Voice) -> Result<Vec<u8>, TtsError> {
+    let Some(api_key) = api_key() else {
+        return Err(TtsError::NotConfigured);
+    };
+    if text.chars().count() > MAX_INPUT_LENGTH {
+        return Err(TtsError::TooLong);
+    }
+
+    info!("Generating speech with voice {}", voice);
+    let res = reqwest::Client::new()
+        .post(format!("{OPENAI_BASE_URL}/audio/speech"))
+        .bearer_auth(api_key.trim())
+        .json(&json!({
+            "model": "tts-1",
+            "input": text,
+            "voice": voice.to_string(),
+            "response_format": "opus",
+        }))
+        .send()
+        .await
+        .map_err(|e| {
+            error!("Failed to send request to OpenAI: {}", e);
+            TtsError::RequestError(e.to_string())
+        })?;
+
+    let status = res.status();
+    if !status.is_success() {
+        let json = res.json::<serde_json::Value>().await.unwrap_or_default();
+        error!("OpenAI returned an error: {:?}", json);
+        let code = json["error"]["code"]
+            .as_str()
+            .map_or_else(|| status.to_string(), str::to_string);
+        return Err(TtsError::ApiError(code));
+    }
+
+    let audio = res
+        .bytes()
+        .await
+        .map_err(|e| TtsError::RequestError(e.to_string()))?;
+    Ok(audio.to_vec())
+}
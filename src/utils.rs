@@ -1,13 +1,410 @@
+use mime::Mime;
+use std::env;
+use std::io::{Cursor, Write};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use teloxide::types::{ChatAction, ChatId};
+use teloxide::utils::markdown;
 use teloxide::{prelude::Requester, types::Message, Bot};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+// Telegram clears the typing status after ~5 seconds, so refresh it a bit earlier
+const TYPING_INTERVAL: Duration = Duration::from_secs(4);
+const TYPING_JITTER_MS: u64 = 500;
+// Stops a leaked guard from sending typing forever, longer than the Lambda timeout anyway
+const TYPING_MAX_LIFETIME: Duration = Duration::from_secs(120);
 
 pub async fn delete_message_delay(bot: &Bot, msg: &Message, delay: u64) {
     tokio::time::sleep(tokio::time::Duration::from_secs(delay)).await;
     bot.delete_message(msg.chat.id, msg.id).await.unwrap();
 }
 
+/// Keeps the typing indicator alive until dropped
+pub struct TypingIndicatorGuard(JoinHandle<()>);
+
+impl Drop for TypingIndicatorGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Sends "typing" right away and then every few seconds, until the returned guard is dropped
+pub fn start_typing_indicator(bot: &Bot, chat_id: ChatId) -> TypingIndicatorGuard {
+    let bot = bot.clone();
+    TypingIndicatorGuard(tokio::spawn(async move {
+        let started = tokio::time::Instant::now();
+        while started.elapsed() < TYPING_MAX_LIFETIME {
+            if let Err(e) = bot.send_chat_action(chat_id, ChatAction::Typing).await {
+                warn!("Failed to send typing indicator: {:?}", e);
+            }
+            // A bit of jitter so busy chats don't all refresh at the same moment
+            let jitter = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| u64::from(d.subsec_nanos()) % TYPING_JITTER_MS)
+                .unwrap_or_default();
+            tokio::time::sleep(TYPING_INTERVAL - Duration::from_millis(jitter)).await;
+        }
+    }))
+}
+
+/// Checks if the sender can change chat settings.
+/// Anyone can in private chats, in groups only admins and the owner can.
+pub async fn is_chat_admin(bot: &Bot, message: &Message) -> bool {
+    if message.chat.is_private() {
+        return true;
+    }
+
+    // Anonymous admins post on behalf of the group itself
+    if message
+        .sender_chat
+        .as_ref()
+        .is_some_and(|chat| chat.id == message.chat.id)
+    {
+        return true;
+    }
+
+    let Some(user) = message.from.as_ref() else {
+        return false;
+    };
+
+    match bot.get_chat_member(message.chat.id, user.id).await {
+        Ok(member) => member.is_privileged(),
+        Err(e) => {
+            error!("Failed to get chat member: {:?}", e);
+            false
+        }
+    }
+}
+
+/// Returns the unique file id of the voice message, video note, video or audio file in the message.
+/// Telegram keeps `file_unique_id` the same for a file across forwards, bots and time,
+/// so a forwarded clip hits the same cache entry as the original.
+pub fn get_unique_file_id(message: &Message) -> Option<&String> {
+    if let Some(voice) = message.voice() {
+        Some(&voice.file.unique_id)
+    } else if let Some(video_note) = message.video_note() {
+        Some(&video_note.file.unique_id)
+    } else if let Some(video) = message.video() {
+        Some(&video.file.unique_id)
+    } else {
+        message.audio().map(|audio| &audio.file.unique_id)
+    }
+}
+
+/// Whether the message has anything that can be transcribed
+pub fn has_audio(message: &Message) -> bool {
+    get_unique_file_id(message).is_some()
+}
+
+/// Returns the caption of the audio, unless it's a command, or else the title of an audio file.
+/// It's passed to Whisper as the prompt, so users can hint names or the topic.
+pub fn caption_prompt(message: &Message) -> Option<&str> {
+    message
+        .caption()
+        .map(str::trim)
+        .filter(|caption| !caption.is_empty() && !caption.starts_with('/'))
+        .or_else(|| message.audio().and_then(|audio| audio.title.as_deref()))
+}
+
+/// "<title> by <performer>" from the metadata of an audio file, shown above its transcription
+pub fn audio_heading(message: &Message) -> Option<String> {
+    let audio = message.audio()?;
+    match (audio.title.as_deref(), audio.performer.as_deref()) {
+        (Some(title), Some(performer)) => Some(format!("🎵 {title} by {performer}")),
+        (Some(title), None) => Some(format!("🎵 {title}")),
+        (None, Some(performer)) => Some(format!("🎵 {performer}")),
+        (None, None) => None,
+    }
+}
+
+/// Parses a comma-separated list of Telegram ids from an env var, None if it's unset or empty.
+/// Entries that aren't numbers are skipped.
+pub fn env_id_list(name: &str) -> Option<Vec<i64>> {
+    let ids = env::var(name).ok().filter(|ids| !ids.trim().is_empty())?;
+    Some(
+        ids.split(',')
+            .filter_map(|id| id.trim().parse().ok())
+            .collect(),
+    )
+}
+
+/// Hides all but the last 4 characters of an API key, so it can be shown in chats and logs
+pub fn mask_key(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    let visible: String = chars[chars.len().saturating_sub(4)..].iter().collect();
+    format!("…{visible}")
+}
+
+/// Returns the duration in seconds of the voice message, video note, video or audio file in the message
+pub fn get_audio_duration(message: &Message) -> Option<u32> {
+    if let Some(voice) = message.voice() {
+        Some(voice.duration.seconds())
+    } else if let Some(video_note) = message.video_note() {
+        Some(video_note.duration.seconds())
+    } else if let Some(video) = message.video() {
+        Some(video.duration.seconds())
+    } else {
+        message.audio().map(|audio| audio.duration.seconds())
+    }
+}
+
+/// Formats seconds as `m:ss`, or `h:mm:ss` for anything longer than an hour
+pub fn format_duration(seconds: u32) -> String {
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+/// Parses "10:00-12:00" (or "1:02:00-1:05:30", "90-120") into start and end seconds.
+/// The inverse of format_duration, returns None unless the end comes after the start.
+pub fn parse_time_range(range: &str) -> Option<(u32, u32)> {
+    let (start, end) = range.trim().split_once('-')?;
+    let (start, end) = (parse_timestamp(start)?, parse_timestamp(end)?);
+    (start < end).then_some((start, end))
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<u32> {
+    let parts: Vec<u32> = timestamp
+        .trim()
+        .split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    // Checked, so a huge hour count is rejected instead of overflowing
+    match parts[..] {
+        [seconds] => Some(seconds),
+        [minutes, seconds] if seconds < 60 => minutes.checked_mul(60)?.checked_add(seconds),
+        [hours, minutes, seconds] if minutes < 60 && seconds < 60 => {
+            hours.checked_mul(3600)?.checked_add(minutes * 60 + seconds)
+        }
+        _ => None,
+    }
+}
+
+// Types the Whisper APIs accept, everything else is rejected before downloading or uploading
+pub const SUPPORTED_MIME_TYPES: &[&str] = &[
+    "audio/flac",
+    "audio/mpeg",
+    "video/mp4",
+    "video/mpeg",
+    "audio/ogg",
+    "audio/mp4",
+    "audio/wav",
+    "video/webm",
+];
+
+// File extensions mapped to a supported mime type
+const AUDIO_EXTENSIONS: &[(&str, &str)] = &[
+    ("flac", "audio/flac"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("mpeg", "video/mpeg"),
+    ("webm", "video/webm"),
+];
+
+// Non-standard names clients use for the supported types
+const MIME_ALIASES: &[(&str, &str)] = &[
+    ("audio/x-flac", "audio/flac"),
+    ("audio/mp3", "audio/mpeg"),
+    ("audio/x-m4a", "audio/mp4"),
+    ("audio/m4a", "audio/mp4"),
+    ("audio/opus", "audio/ogg"),
+    ("audio/x-wav", "audio/wav"),
+    ("audio/wave", "audio/wav"),
+    ("audio/vnd.wave", "audio/wav"),
+];
+
+pub fn mime_from_extension(extension: &str) -> Option<Mime> {
+    let extension = extension.to_lowercase();
+    AUDIO_EXTENSIONS
+        .iter()
+        .find(|(ext, _)| *ext == extension)
+        .map(|(_, mime)| Mime::from_str(mime).unwrap())
+}
+
+/// Maps aliases and guesses (like application/octet-stream) to a type Groq accepts.
+/// The file name's extension is used when the type is unknown, and an untyped file is sent as audio/mpeg.
+/// Other audio types (like audio/amr) are returned as they are, so they get rejected as unsupported.
+pub fn normalize_mime(mime: Mime, file_name: Option<&str>) -> Mime {
+    if SUPPORTED_MIME_TYPES.contains(&mime.essence_str()) {
+        return mime;
+    }
+    if let Some((_, alias)) = MIME_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == mime.essence_str())
+    {
+        return Mime::from_str(alias).unwrap();
+    }
+    if let Some(mime) = file_name
+        .and_then(|name| name.rsplit_once('.'))
+        .and_then(|(_, extension)| mime_from_extension(extension))
+    {
+        return mime;
+    }
+    if mime == mime::APPLICATION_OCTET_STREAM {
+        return Mime::from_str("audio/mpeg").unwrap();
+    }
+    mime
+}
+
+/// Sniffs the type from the first bytes of the file, which beats a wrong type from Telegram or a misleading extension.
+/// Only audio and video types are trusted, for anything else the declared type is kept.
+pub fn resolve_mime(bytes: &[u8], declared: Mime) -> Mime {
+    let Some(kind) = infer::get(bytes) else {
+        return declared;
+    };
+    if !matches!(
+        kind.matcher_type(),
+        infer::MatcherType::Audio | infer::MatcherType::Video
+    ) {
+        return declared;
+    }
+
+    match Mime::from_str(kind.mime_type()) {
+        Ok(sniffed) => {
+            let sniffed = normalize_mime(sniffed, None);
+            if sniffed.essence_str() != declared.essence_str() {
+                info!("File looks like {} instead of {}", sniffed, declared);
+            }
+            sniffed
+        }
+        Err(_) => declared,
+    }
+}
+
+/// The type of the voice message or audio file, as it will be uploaded (see normalize_mime).
+/// None for videos, their audio track may still be extracted into a supported type.
+pub fn audio_mime(message: &Message) -> Option<Mime> {
+    if let Some(voice) = message.voice() {
+        Some(normalize_mime(
+            voice
+                .mime_type
+                .clone()
+                .unwrap_or_else(|| Mime::from_str("audio/ogg").unwrap()),
+            None,
+        ))
+    } else {
+        message.audio().map(|audio| {
+            normalize_mime(
+                audio
+                    .mime_type
+                    .clone()
+                    .unwrap_or_else(|| Mime::from_str("audio/mpeg").unwrap()),
+                audio.file_name.as_deref(),
+            )
+        })
+    }
+}
+
+/// The type of the file as it is downloaded, before sniffing it or extracting the audio track.
+/// Video notes don't report a type, they're always mp4.
+pub fn source_mime(message: &Message) -> Option<Mime> {
+    if message.video_note().is_some() {
+        Some(Mime::from_str("video/mp4").unwrap())
+    } else if let Some(video) = message.video() {
+        Some(normalize_mime(
+            video
+                .mime_type
+                .clone()
+                .unwrap_or_else(|| Mime::from_str("video/mp4").unwrap()),
+            video.file_name.as_deref(),
+        ))
+    } else {
+        audio_mime(message)
+    }
+}
+
+/// Builds a zip archive in memory from (file name, contents) pairs
+pub fn zip_files(files: &[(&str, String)]) -> zip::result::ZipResult<Vec<u8>> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default();
+    for (name, contents) in files {
+        zip.start_file(*name, options)?;
+        zip.write_all(contents.as_bytes())?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Wraps the text in a MarkdownV2 code block so it can be copied with one tap.
+/// Inside a code block only ` and \ have to be escaped.
+pub fn format_as_markdown(text: &str) -> String {
+    markdown::code_block(text.trim())
+}
+
+/// Same as format_as_markdown, but split into code blocks that each fit in one message.
+/// Escaping makes the text longer, so a part that still doesn't fit is split again.
+pub fn format_as_markdown_parts(text: &str, max_length: usize) -> Vec<String> {
+    let formatted = format_as_markdown(text);
+    if formatted.chars().count() <= max_length {
+        return vec![formatted];
+    }
+
+    // The fences take 8 characters
+    let size = if text.len() > max_length {
+        max_length.saturating_sub(8)
+    } else {
+        text.len() / 2
+    };
+    let parts = split_string(text, size);
+    if parts.len() < 2 {
+        // A single word that long can't be split, let Telegram reject it
+        return vec![formatted];
+    }
+
+    parts
+        .iter()
+        .flat_map(|part| format_as_markdown_parts(part, max_length))
+        .collect()
+}
+
 pub fn split_string(input: &str, max_length: usize) -> Vec<String> {
     let mut result = Vec::new();
     let mut current_chunk = String::new();
+
+    // Whole lines stay together, so timestamps and speaker labels keep their line breaks
+    for line in input.lines() {
+        let pieces = if line.len() > max_length {
+            split_words(line, max_length)
+        } else {
+            vec![line.to_string()]
+        };
+
+        for piece in pieces {
+            if current_chunk.len() + piece.len() + 1 > max_length && !current_chunk.is_empty() {
+                result.push(current_chunk.trim_end().to_string());
+                current_chunk = String::new();
+            }
+            // Don't start a chunk with an empty line
+            if current_chunk.is_empty() && piece.trim().is_empty() {
+                continue;
+            }
+
+            if !current_chunk.is_empty() {
+                current_chunk.push('\n');
+            }
+            current_chunk.push_str(&piece);
+        }
+    }
+
+    if !current_chunk.trim().is_empty() {
+        result.push(current_chunk.trim_end().to_string());
+    }
+
+    result
+}
+
+// Only used for a single line that doesn't fit in a message on its own
+fn split_words(input: &str, max_length: usize) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut current_chunk = String::new();
     let mut current_length = 0;
 
     for word in input.split_whitespace() {
@@ -32,3 +429,295 @@ pub fn split_string(input: &str, max_length: usize) -> Vec<String> {
 
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mime(mime: &str) -> Mime {
+        Mime::from_str(mime).unwrap()
+    }
+
+    // An mp4 "ftyp" box with the given major brand
+    fn ftyp(brand: &[u8; 4]) -> Vec<u8> {
+        let mut bytes = vec![0, 0, 0, 0x20];
+        bytes.extend_from_slice(b"ftyp");
+        bytes.extend_from_slice(brand);
+        bytes.extend_from_slice(&[0; 20]);
+        bytes
+    }
+
+    #[test]
+    fn parse_time_range_minutes() {
+        assert_eq!(parse_time_range("10:00-12:00"), Some((600, 720)));
+        assert_eq!(parse_time_range(" 0:05 - 1:30 "), Some((5, 90)));
+    }
+
+    #[test]
+    fn parse_time_range_seconds() {
+        assert_eq!(parse_time_range("90-120"), Some((90, 120)));
+    }
+
+    #[test]
+    fn parse_time_range_hours() {
+        assert_eq!(parse_time_range("1:02:00-1:05:30"), Some((3720, 3930)));
+        assert_eq!(parse_time_range("59:00-1:00:30"), Some((3540, 3630)));
+    }
+
+    #[test]
+    fn parse_time_range_reversed_or_empty() {
+        assert_eq!(parse_time_range("12:00-10:00"), None);
+        assert_eq!(parse_time_range("1:00-1:00"), None);
+    }
+
+    #[test]
+    fn parse_time_range_invalid() {
+        assert_eq!(parse_time_range("12:60-13:00"), None);
+        assert_eq!(parse_time_range("1:60:00-2:00:00"), None);
+        assert_eq!(parse_time_range("10:00"), None);
+        assert_eq!(parse_time_range("a-b"), None);
+        assert_eq!(parse_time_range("1:2:3:4-5"), None);
+    }
+
+    #[test]
+    fn parse_time_range_overflow() {
+        assert_eq!(parse_time_range("0-4294967295:00"), None);
+        assert_eq!(parse_time_range("0-4294967295:00:00"), None);
+        assert_eq!(parse_time_range("0-99999999999"), None);
+    }
+
+    #[test]
+    fn normalize_mime_keeps_supported() {
+        assert_eq!(normalize_mime(mime("audio/ogg"), None), mime("audio/ogg"));
+        assert_eq!(
+            normalize_mime(mime("video/mp4"), Some("clip.mp3")),
+            mime("video/mp4")
+        );
+    }
+
+    #[test]
+    fn normalize_mime_maps_aliases() {
+        assert_eq!(normalize_mime(mime("audio/x-m4a"), None), mime("audio/mp4"));
+        assert_eq!(normalize_mime(mime("audio/x-wav"), None), mime("audio/wav"));
+        assert_eq!(
+            normalize_mime(mime("audio/x-flac"), None),
+            mime("audio/flac")
+        );
+    }
+
+    #[test]
+    fn normalize_mime_falls_back_to_extension() {
+        assert_eq!(
+            normalize_mime(mime("application/octet-stream"), Some("memo.M4A")),
+            mime("audio/mp4")
+        );
+        assert_eq!(
+            normalize_mime(mime("audio/x-unknown"), Some("song.final.opus")),
+            mime("audio/ogg")
+        );
+    }
+
+    #[test]
+    fn normalize_mime_untyped_is_mpeg() {
+        assert_eq!(
+            normalize_mime(mime("application/octet-stream"), None),
+            mime("audio/mpeg")
+        );
+        assert_eq!(
+            normalize_mime(mime("application/octet-stream"), Some("recording.xyz")),
+            mime("audio/mpeg")
+        );
+    }
+
+    #[test]
+    fn normalize_mime_keeps_other_audio() {
+        assert_eq!(normalize_mime(mime("audio/amr"), None), mime("audio/amr"));
+        assert_eq!(
+            normalize_mime(mime("audio/aac"), Some("voice.amr")),
+            mime("audio/aac")
+        );
+    }
+
+    #[test]
+    fn mime_from_extension_ignores_case() {
+        assert_eq!(mime_from_extension("MP3"), Some(mime("audio/mpeg")));
+        assert_eq!(mime_from_extension("flac"), Some(mime("audio/flac")));
+        assert_eq!(mime_from_extension("txt"), None);
+    }
+
+    #[test]
+    fn resolve_mime_ogg() {
+        let bytes = b"OggS\0\x02\0\0\0\0\0\0\0\0";
+        assert_eq!(resolve_mime(bytes, mime("audio/ogg")), mime("audio/ogg"));
+        assert_eq!(resolve_mime(bytes, mime("audio/mpeg")), mime("audio/ogg"));
+    }
+
+    #[test]
+    fn resolve_mime_wav() {
+        let bytes = b"RIFF\x24\0\0\0WAVEfmt \x10\0\0\0";
+        assert_eq!(resolve_mime(bytes, mime("audio/wav")), mime("audio/wav"));
+        assert_eq!(resolve_mime(bytes, mime("audio/ogg")), mime("audio/wav"));
+    }
+
+    #[test]
+    fn resolve_mime_mp3() {
+        let bytes = b"ID3\x04\0\0\0\0\0\0";
+        assert_eq!(resolve_mime(bytes, mime("audio/mpeg")), mime("audio/mpeg"));
+        assert_eq!(
+            resolve_mime(bytes, mime("application/octet-stream")),
+            mime("audio/mpeg")
+        );
+    }
+
+    #[test]
+    fn resolve_mime_m4a() {
+        let bytes = ftyp(b"M4A ");
+        assert_eq!(resolve_mime(&bytes, mime("audio/mp4")), mime("audio/mp4"));
+        assert_eq!(resolve_mime(&bytes, mime("audio/mpeg")), mime("audio/mp4"));
+    }
+
+    #[test]
+    fn resolve_mime_mp4() {
+        let bytes = ftyp(b"isom");
+        assert_eq!(resolve_mime(&bytes, mime("video/mp4")), mime("video/mp4"));
+        assert_eq!(resolve_mime(&bytes, mime("audio/ogg")), mime("video/mp4"));
+    }
+
+    #[test]
+    fn resolve_mime_keeps_declared_when_unknown() {
+        assert_eq!(
+            resolve_mime(b"not audio at all", mime("audio/ogg")),
+            mime("audio/ogg")
+        );
+        // Not an audio or video type, so it's not trusted
+        assert_eq!(
+            resolve_mime(b"%PDF-1.7\n", mime("audio/mpeg")),
+            mime("audio/mpeg")
+        );
+    }
+
+    #[test]
+    fn format_as_markdown_escapes_backticks() {
+        assert_eq!(format_as_markdown("run `ls`"), "```\nrun \\`ls\\`\n```");
+    }
+
+    #[test]
+    fn format_as_markdown_escapes_backslashes() {
+        assert_eq!(
+            format_as_markdown("C:\\Users\\duck"),
+            "```\nC:\\\\Users\\\\duck\n```"
+        );
+    }
+
+    #[test]
+    fn format_as_markdown_only_escapes_code_characters() {
+        // Outside a code block every one of these would need a backslash, inside only ` and \ do
+        let special = "_*[]()~>#+-=|{}.!";
+        assert_eq!(
+            format_as_markdown(&format!("{special}`\\")),
+            format!("```\n{special}\\`\\\\\n```")
+        );
+    }
+
+    #[test]
+    fn format_as_markdown_trims() {
+        assert_eq!(format_as_markdown("  hello\n"), "```\nhello\n```");
+    }
+
+    #[test]
+    fn format_as_markdown_parts_keeps_short_text_whole() {
+        assert_eq!(
+            format_as_markdown_parts("line one\nline two", 4096),
+            vec![format_as_markdown("line one\nline two")]
+        );
+    }
+
+    #[test]
+    fn format_as_markdown_parts_splits_after_escaping() {
+        // 3000 characters of text, but twice that once every backtick is escaped
+        let text = "`a` ".repeat(750);
+        assert!(text.len() <= 4096);
+        assert!(format_as_markdown(&text).chars().count() > 4096);
+
+        let parts = format_as_markdown_parts(&text, 4096);
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(part.chars().count() <= 4096);
+            assert!(part.starts_with("```\n") && part.ends_with("\n```"));
+        }
+        let backticks: usize = parts.iter().map(|part| part.matches("\\`").count()).sum();
+        assert_eq!(backticks, 1500);
+    }
+
+    #[test]
+    fn format_as_markdown_parts_keeps_escapes_whole() {
+        // Every backtick and backslash doubles in length, and a split mustn't land between the two
+        let text = "a`b\\c ".repeat(1200);
+        let parts = format_as_markdown_parts(&text, 4096);
+        assert!(parts.len() > 1);
+        for part in &parts {
+            assert!(part.chars().count() <= 4096);
+            let body = part
+                .strip_prefix("```\n")
+                .and_then(|body| body.strip_suffix("\n```"))
+                .unwrap();
+            // Undoing the escapes must give back whole words, no dangling backslash
+            let mut chars = body.chars();
+            let mut unescaped = String::new();
+            while let Some(c) = chars.next() {
+                if c == '\\' {
+                    let escaped = chars.next().expect("escape split from its character");
+                    assert!(escaped == '`' || escaped == '\\');
+                    unescaped.push(escaped);
+                } else {
+                    unescaped.push(c);
+                }
+            }
+            assert!(unescaped.split_whitespace().all(|word| word == "a`b\\c"));
+        }
+    }
+
+    #[test]
+    fn format_as_markdown_parts_splits_long_text() {
+        let text = "word ".repeat(2000);
+        let parts = format_as_markdown_parts(&text, 4096);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|part| part.chars().count() <= 4096));
+    }
+
+    #[test]
+    fn split_string_keeps_line_breaks() {
+        let text = "[0:00] Hello there\n[0:05] General Kenobi\n[0:09] You are a bold one";
+        assert_eq!(split_string(text, 4096), vec![text.to_string()]);
+    }
+
+    #[test]
+    fn split_string_splits_between_lines() {
+        let text = "[0:00] first line\n[0:05] second line\n[0:09] third line";
+        assert_eq!(
+            split_string(text, 40),
+            vec![
+                "[0:00] first line\n[0:05] second line".to_string(),
+                "[0:09] third line".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_string_splits_long_line_between_words() {
+        let text = "short\none two three four five six";
+        let parts = split_string(text, 10);
+        assert_eq!(parts, vec!["short", "one two", "three four", "five six"]);
+        assert!(parts.iter().all(|part| part.len() <= 10));
+    }
+
+    #[test]
+    fn split_string_drops_blank_lines_at_chunk_edges() {
+        let text = "Speaker 1: hi\n\nSpeaker 2: hello";
+        assert_eq!(split_string(text, 4096), vec![text.to_string()]);
+        assert_eq!(
+            split_string(text, 16),
+            vec!["Speaker 1: hi".to_string(), "Speaker 2: hello".to_string()]
+        );
+    }
+}